@@ -1,6 +1,7 @@
 use model::{AddressBook, Peer, Permission, User};
 use sqlx::Result;
 use sqlx::SqlitePool;
+use uuid::Uuid;
 
 pub mod model;
 
@@ -42,12 +43,16 @@ create table if not exists address_book
         )
         .execute(&self.pool)
         .await?;
+        // 旧版本数据库没有组织字段，已存在时忽略错误
+        let _ = sqlx::query("alter table user add column org text")
+            .execute(&self.pool)
+            .await;
 
         let _ = self
-            .create_user("admin", "admin", Permission::Admin, false)
+            .create_user("admin", "admin", Permission::Admin, false, None)
             .await;
         let _ = self
-            .create_user("admin", "admin", Permission::User, false)
+            .create_user("admin", "admin", Permission::User, false, None)
             .await;
 
         Ok(())
@@ -76,14 +81,18 @@ impl DbPool {
         password: &str,
         perm: Permission,
         disabled: bool,
+        org: Option<&str>,
     ) -> Result<()> {
-        sqlx::query("insert into user values (?, ?, ?, ?);")
-            .bind(username)
-            .bind(password)
-            .bind(perm)
-            .bind(disabled)
-            .execute(&self.pool)
-            .await?;
+        sqlx::query(
+            "insert into user(username, password, perm, disabled, org) values (?, ?, ?, ?, ?);",
+        )
+        .bind(username)
+        .bind(password)
+        .bind(perm)
+        .bind(disabled)
+        .bind(org)
+        .execute(&self.pool)
+        .await?;
 
         if perm == Permission::User {
             let _ = sqlx::query("insert into address_book(username, updated_at) values (?, ?)")
@@ -157,6 +166,54 @@ impl DbPool {
             .await
             .map(|_| ())
     }
+
+    /// 设置用户所属组织，为空时清除
+    pub async fn set_user_org(&self, username: &str, perm: Permission, org: &str) -> Result<()> {
+        sqlx::query("update user set org = ? where username = ? and perm = ?")
+            .bind(if org.is_empty() { None } else { Some(org) })
+            .bind(username)
+            .bind(perm)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// 设备归属操作
+///
+/// `peer`表由hbbs创建，仅当hbbs与本服务使用同一个数据库时有效
+impl DbPool {
+    /// 用户登录后将设备归属到该用户，设备ID与UUID需与hbbs中注册的一致
+    pub async fn set_peer_owner(&self, id: &str, uuid: &Uuid, username: &str) -> Result<bool> {
+        let registered: Option<(Vec<u8>,)> = sqlx::query_as("select uuid from peer where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let matched = registered
+            .and_then(|(x,)| String::from_utf8(x).ok())
+            .and_then(|x| Uuid::parse_str(&x).ok())
+            .map(|x| &x == uuid)
+            .unwrap_or_default();
+        if !matched {
+            return Ok(false);
+        }
+        sqlx::query("update peer set user = ? where id = ?")
+            .bind(username.as_bytes())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    /// 用户登出后解除设备归属
+    pub async fn clear_peer_owner(&self, id: &str, username: &str) -> Result<()> {
+        sqlx::query("update peer set user = null where id = ? and user = ?")
+            .bind(id)
+            .bind(username.as_bytes())
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
 }
 
 /// 地址簿操作
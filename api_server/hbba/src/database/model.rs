@@ -15,6 +15,9 @@ pub struct User {
     pub password: String,
     pub perm: Permission,
     pub disabled: bool,
+    /// 所属组织，为空时用户自成一个组织
+    #[serde(default)]
+    pub org: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
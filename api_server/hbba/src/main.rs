@@ -23,6 +23,8 @@ async fn main() {
         )
         .init();
 
+    server::jwt::check_secret();
+
     let pool = DbPool::new("sqlite://./db_v2.sqlite3")
         .await
         .expect("数据库连接失败");
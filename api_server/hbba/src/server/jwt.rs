@@ -5,9 +5,24 @@ use axum::extract::{FromRequest, RequestParts};
 use axum::http::StatusCode;
 use jsonwebtoken::{Algorithm, Validation};
 
-const SECRET: &[u8] = b"rustdesk api server";
+const DEFAULT_SECRET: &str = "rustdesk api server";
 const ALGORITHM: Algorithm = Algorithm::HS512;
 
+/// `API_SECRET`, shared with hbbs and hbbr to verify the tokens
+fn secret() -> Vec<u8> {
+    std::env::var("API_SECRET")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| DEFAULT_SECRET.to_owned())
+        .into_bytes()
+}
+
+pub fn check_secret() {
+    if secret() == DEFAULT_SECRET.as_bytes() {
+        warn!("API_SECRET未设置，正在使用公开的默认密钥签发token");
+    }
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -44,7 +59,7 @@ impl Claims {
         };
 
         let header = jsonwebtoken::Header::new(ALGORITHM);
-        let key = jsonwebtoken::EncodingKey::from_secret(SECRET);
+        let key = jsonwebtoken::EncodingKey::from_secret(&secret());
         jsonwebtoken::encode(&header, &claims, &key).unwrap()
     }
 }
@@ -74,7 +89,7 @@ impl<B: Send> FromRequest<B> for Claims {
             })
             .and_then(|_| iter.next())
             .and_then(|token| {
-                let key = jsonwebtoken::DecodingKey::from_secret(SECRET);
+                let key = jsonwebtoken::DecodingKey::from_secret(&secret());
                 let validation = Validation::new(ALGORITHM);
                 jsonwebtoken::decode(token, &key, &validation).ok()
             })
@@ -77,6 +77,7 @@ pub async fn get_users(claims: Claims, pool: Extension<DbPool>) -> (StatusCode,
                             username: u.username,
                             perm: u.perm,
                             disabled: u.disabled,
+                            org: u.org,
                         })
                         .collect(),
                 }),
@@ -103,7 +104,13 @@ pub async fn crate_user(
     }
 
     match pool
-        .create_user(&user.username, &user.password, user.perm, user.disabled)
+        .create_user(
+            &user.username,
+            &user.password,
+            user.perm,
+            user.disabled,
+            user.org.as_deref(),
+        )
         .await
     {
         Ok(_) => (StatusCode::OK, Response::ok(())),
@@ -146,6 +153,16 @@ pub async fn update_user(
         return e;
     }
 
+    if let Some(org) = &user.org {
+        if let Err(e) = pool.set_user_org(&user.username, user.perm, org).await {
+            warn!(error = %e, "设置用户组织时出现异常");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Response::error("设置用户组织时出现错误，请重试或联系管理员"),
+            );
+        }
+    }
+
     match pool
         .set_user_disabled(&user.username, user.perm, user.disabled)
         .await
@@ -199,6 +216,9 @@ pub struct UpdateUser {
     pub username: String,
     pub perm: Permission,
     pub disabled: bool,
+    /// 设置时修改所属组织，空字符串清除
+    #[serde(default)]
+    pub org: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -217,6 +237,7 @@ pub struct User {
     pub username: String,
     pub perm: Permission,
     pub disabled: bool,
+    pub org: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,6 +17,18 @@ pub async fn login(Json(login): Json<Login>, pool: Extension<DbPool>) -> Respons
             if user.disabled {
                 Response::error("该账号已被禁用,请联系管理员")
             } else {
+                match pool
+                    .set_peer_owner(
+                        &login.local_peer.id,
+                        &login.local_peer.uuid,
+                        &login.username,
+                    )
+                    .await
+                {
+                    Ok(true) => debug!(id = %login.local_peer.id, "设备已归属到该用户"),
+                    Ok(false) => debug!(id = %login.local_peer.id, "设备未注册或UUID不一致"),
+                    Err(e) => warn!(error = %e, "设置设备归属时发生错误"),
+                }
                 let access_token = Claims::gen_user_token(login.username, login.local_peer);
                 Response::ok(LoginResponse {
                     access_token,
@@ -53,9 +65,19 @@ pub async fn current_user(
         .unwrap_or_else(|r| (StatusCode::UNAUTHORIZED, r))
 }
 
-#[instrument]
-pub async fn logout(Json(_local_peer): Json<LocalPeer>, _claims: Claims) -> Response<()> {
+#[instrument(skip(pool))]
+pub async fn logout(
+    Json(local_peer): Json<LocalPeer>,
+    claims: Claims,
+    pool: Extension<DbPool>,
+) -> Response<()> {
     debug!("user logout");
+    if let Err(e) = pool
+        .clear_peer_owner(&local_peer.id, &claims.username)
+        .await
+    {
+        warn!(error = %e, "解除设备归属时发生错误");
+    }
     Response::ok(())
 }
 
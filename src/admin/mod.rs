@@ -0,0 +1,117 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::IntoResponse,
+    Extension, Router,
+};
+use hbb_common::{log, ResultType};
+use serde_derive::Serialize;
+use std::net::SocketAddr;
+
+pub(crate) mod relay;
+pub(crate) mod rendezvous;
+
+#[derive(Clone)]
+struct AdminToken(String);
+
+/// Json body of every admin api response, `{"error": ..., <data fields>}`,
+/// the same shape the api server (hbba) uses.
+#[derive(Debug, Serialize)]
+pub(crate) struct Response<T> {
+    error: Option<String>,
+    #[serde(flatten)]
+    data: Option<T>,
+}
+
+impl<T> Response<T> {
+    #[inline]
+    pub(crate) fn ok(data: T) -> Self {
+        Self {
+            error: None,
+            data: Some(data),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn error<S: ToString>(error: S) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            data: None,
+        }
+    }
+}
+
+impl<T: serde::Serialize> IntoResponse for Response<T> {
+    fn into_response(self) -> axum::response::Response {
+        axum::Json(self).into_response()
+    }
+}
+
+/// Extractor guarding the admin routes, the request must carry
+/// `Authorization: Bearer <admin-token>`.
+pub(crate) struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = (StatusCode, Response<()>);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .extensions()
+            .get::<AdminToken>()
+            .map(|x| x.0.clone())
+            .unwrap_or_default();
+        let authorized = !token.is_empty()
+            && req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| {
+                    let mut it = v.split_whitespace();
+                    match (it.next(), it.next()) {
+                        (Some(prefix), Some(v)) if prefix.eq_ignore_ascii_case("bearer") => {
+                            Some(eq_const_time(v.as_bytes(), token.as_bytes()))
+                        }
+                        _ => None,
+                    }
+                })
+                .unwrap_or(false);
+        if authorized {
+            Ok(Admin)
+        } else {
+            Err((StatusCode::UNAUTHORIZED, Response::error("unauthorized")))
+        }
+    }
+}
+
+/// Compare without returning at the first difference, not to leak the
+/// token through timing.
+fn eq_const_time(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `/metrics` in prometheus text format, not guarded by the admin token.
+pub(crate) fn metrics_response() -> impl IntoResponse {
+    let (content_type, body) = crate::metrics::gather();
+    ([(CONTENT_TYPE, content_type)], body)
+}
+
+pub(crate) async fn serve(addr: &str, token: &str, router: Router) -> ResultType<()> {
+    let addr = addr.parse::<SocketAddr>()?;
+    if token.is_empty() {
+        log::warn!("admin-token is not set, all admin api requests will be rejected");
+    }
+    log::info!("Listening on http {}, admin api", addr);
+    axum::Server::bind(&addr)
+        .serve(
+            router
+                .layer(Extension(AdminToken(token.to_owned())))
+                .into_make_service(),
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use super::{metrics_response, Admin, Response};
+use crate::bandwidth_policy::Policy;
+use crate::ip_list::{Entry, IpList};
+use crate::relay_server::*;
+use async_speed_limit::Limiter;
+use axum::{extract::Path, response::IntoResponse, routing::get, Extension, Json, Router};
+use serde_derive::{Deserialize, Serialize};
+
+pub(crate) fn router(limiter: Limiter) -> Router {
+    Router::new()
+        .route(
+            "/api/blacklist",
+            get(get_blacklist)
+                .post(add_blacklist)
+                .delete(clear_blacklist),
+        )
+        .route(
+            "/api/blacklist/:ip",
+            get(check_blacklist).delete(remove_blacklist),
+        )
+        .route(
+            "/api/blocklist",
+            get(get_blocklist)
+                .post(add_blocklist)
+                .delete(clear_blocklist),
+        )
+        .route(
+            "/api/blocklist/:ip",
+            get(check_blocklist).delete(remove_blocklist),
+        )
+        .route("/api/params", get(get_relay_params).put(set_relay_params))
+        .route("/api/bandwidth-policy", get(get_bandwidth_policy))
+        .route("/api/usage", get(get_usage))
+        .route("/api/drain", get(get_drain).post(start_drain))
+        .route("/metrics", get(metrics))
+        .layer(Extension(limiter))
+}
+
+#[derive(Debug, Serialize)]
+struct Ips {
+    ips: Vec<IpEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct IpEntry {
+    ip: String,
+    /// none if permanent
+    remaining_secs: Option<u64>,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddIps {
+    ips: Vec<String>,
+    /// permanent if none or 0
+    #[serde(default)]
+    secs: Option<u64>,
+    #[serde(default)]
+    reason: String,
+}
+
+impl AddIps {
+    fn entry(&self) -> Entry {
+        Entry::new(self.secs.filter(|x| *x > 0), &self.reason)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Exists {
+    exists: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Drain {
+    draining: bool,
+    /// relays still running
+    relays: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Relays {
+    relays: Vec<RelayUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelayUsage {
+    addr: String,
+    elapsed_secs: usize,
+    total_bytes: usize,
+    /// in kb/s
+    highest_speed: usize,
+    /// in kb/s
+    average_speed: usize,
+    /// in kb/s
+    speed: usize,
+}
+
+#[inline]
+async fn list(list: &IpList) -> Response<Ips> {
+    Response::ok(Ips {
+        ips: list
+            .list()
+            .await
+            .into_iter()
+            .map(|(ip, entry)| IpEntry {
+                ip,
+                remaining_secs: entry.remaining(),
+                reason: entry.reason,
+            })
+            .collect(),
+    })
+}
+
+#[inline]
+async fn check(list: &IpList, ip: &str) -> Response<Exists> {
+    Response::ok(Exists {
+        exists: list.contains(ip).await,
+    })
+}
+
+async fn get_blacklist(_: Admin) -> Response<Ips> {
+    list(&BLACKLIST).await
+}
+
+async fn check_blacklist(_: Admin, Path(ip): Path<String>) -> Response<Exists> {
+    check(&BLACKLIST, &ip).await
+}
+
+async fn add_blacklist(_: Admin, Json(ips): Json<AddIps>) -> Response<()> {
+    BLACKLIST
+        .add(ips.ips.iter().map(|x| x.as_str()), ips.entry())
+        .await;
+    Response::ok(())
+}
+
+async fn remove_blacklist(_: Admin, Path(ip): Path<String>) -> Response<()> {
+    BLACKLIST.remove([ip.as_str()]).await;
+    Response::ok(())
+}
+
+async fn clear_blacklist(_: Admin) -> Response<()> {
+    BLACKLIST.clear().await;
+    Response::ok(())
+}
+
+async fn get_blocklist(_: Admin) -> Response<Ips> {
+    list(&BLOCKLIST).await
+}
+
+async fn check_blocklist(_: Admin, Path(ip): Path<String>) -> Response<Exists> {
+    check(&BLOCKLIST, &ip).await
+}
+
+async fn add_blocklist(_: Admin, Json(ips): Json<AddIps>) -> Response<()> {
+    BLOCKLIST
+        .add(ips.ips.iter().map(|x| x.as_str()), ips.entry())
+        .await;
+    Response::ok(())
+}
+
+async fn remove_blocklist(_: Admin, Path(ip): Path<String>) -> Response<()> {
+    BLOCKLIST.remove([ip.as_str()]).await;
+    Response::ok(())
+}
+
+async fn clear_blocklist(_: Admin) -> Response<()> {
+    BLOCKLIST.clear().await;
+    Response::ok(())
+}
+
+async fn get_relay_params(_: Admin) -> Response<Params> {
+    Response::ok(get_params())
+}
+
+async fn set_relay_params(
+    _: Admin,
+    Json(params): Json<Params>,
+    limiter: Extension<Limiter>,
+) -> Response<Params> {
+    set_params(&params, &limiter);
+    Response::ok(get_params())
+}
+
+async fn get_bandwidth_policy(_: Admin) -> Response<Policy> {
+    Response::ok((*POLICY.get().await).clone())
+}
+
+async fn get_usage(_: Admin) -> Response<Relays> {
+    let mut relays: Vec<RelayUsage> = USAGE
+        .read()
+        .await
+        .iter()
+        .filter(|(_, (elapsed, ..))| *elapsed > 0)
+        .map(|(addr, (elapsed, total, highest, speed))| RelayUsage {
+            addr: addr.clone(),
+            elapsed_secs: elapsed / 1000,
+            total_bytes: total / 8,
+            highest_speed: *highest,
+            average_speed: total / elapsed,
+            speed: *speed,
+        })
+        .collect();
+    relays.sort_by(|a, b| b.total_bytes.cmp(&a.total_bytes));
+    Response::ok(Relays { relays })
+}
+
+async fn get_drain(_: Admin) -> Response<Drain> {
+    Response::ok(Drain {
+        draining: is_draining(),
+        relays: USAGE.read().await.len(),
+    })
+}
+
+async fn start_drain(admin: Admin) -> Response<Drain> {
+    drain();
+    get_drain(admin).await
+}
+
+async fn metrics() -> impl IntoResponse {
+    update_metrics().await;
+    metrics_response()
+}
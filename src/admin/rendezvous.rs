@@ -0,0 +1,484 @@
+use super::{metrics_response, Admin, Response};
+use crate::connection_policy::Rules;
+use crate::events;
+use crate::peer::*;
+use crate::rendezvous_server::{RendezvousServer, Sender};
+use axum::{
+    extract::{Path, Query},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use hbb_common::{
+    futures::stream::{self, Stream},
+    tokio::sync::broadcast::error::RecvError,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+const PAGE_SIZE: usize = 10;
+
+pub(crate) fn router(tx: Sender) -> Router {
+    Router::new()
+        .route(
+            "/api/relay-servers",
+            get(get_relay_servers).put(set_relay_servers),
+        )
+        .route("/api/relay-loads", get(get_relay_loads))
+        .route("/api/relay-health", get(get_relay_health))
+        .route(
+            "/api/always-use-relay",
+            get(get_always_use_relay).put(set_always_use_relay),
+        )
+        .route("/api/ip-blocker", get(get_ip_blocker))
+        .route("/api/ip-blocker/:ip", delete(delete_ip_blocker))
+        .route("/api/ip-changes", get(get_ip_changes))
+        .route("/api/ip-changes/:id", delete(delete_ip_changes))
+        .route("/api/test-geo", get(test_geo))
+        .route("/api/reload-geo", post(reload_geo))
+        .route("/api/connection-policy", get(get_connection_policy))
+        .route("/api/cluster", get(get_cluster))
+        .route("/api/online", post(get_online))
+        .route("/api/events", get(get_events))
+        .route("/api/peers/:id", get(get_peer))
+        .route("/api/peers/:id/disabled", put(set_peer_disabled))
+        .route("/metrics", get(metrics))
+        .layer(Extension(tx))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RelayServers {
+    relay_servers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelayLoads {
+    relay_loads: Vec<RelayLoad>,
+}
+
+/// Load of a healthy relay server, none if it does not report it.
+#[derive(Debug, Serialize)]
+struct RelayLoad {
+    relay_server: String,
+    weight: f64,
+    sessions: Option<i32>,
+    /// in Mb/s
+    bandwidth: Option<f64>,
+    total_bandwidth: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelayHealths {
+    relay_health: Vec<RelayHealth>,
+}
+
+/// Health of a configured relay server, all none if it is not probed yet.
+#[derive(Debug, Serialize)]
+struct RelayHealth {
+    relay_server: String,
+    healthy: bool,
+    failures: Option<u32>,
+    successes: Option<u32>,
+    /// in ms, of the last successful probe
+    latency: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OnlineQuery {
+    ids: Vec<String>,
+    /// the key of hbbs, if it has one
+    #[serde(default)]
+    licence_key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OnlineStates {
+    states: Vec<OnlineState>,
+}
+
+#[derive(Debug, Serialize)]
+struct OnlineState {
+    id: String,
+    online: bool,
+    /// unix secs of the last register, none if unknown
+    last_seen: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClusterNodes {
+    nodes: Vec<ClusterNode>,
+}
+
+/// Another node of the cluster, with the peers registered on it.
+#[derive(Debug, Serialize)]
+struct ClusterNode {
+    node: String,
+    peers: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerDisabled {
+    disabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AlwaysUseRelay {
+    always_use_relay: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Page {
+    #[serde(default)]
+    start: usize,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+impl Page {
+    fn range<T>(&self, it: impl Iterator<Item = T>) -> Vec<T> {
+        it.skip(self.start)
+            .take(self.limit.unwrap_or(PAGE_SIZE))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct List<T> {
+    total: usize,
+    items: Vec<T>,
+}
+
+#[derive(Debug, Serialize)]
+struct IpBlocker {
+    ip: String,
+    /// register_pk requests in the current window
+    requests: u32,
+    last_request_secs: u64,
+    /// distinct ids registered from this ip today
+    ids: usize,
+    last_id_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct IpChanges {
+    id: String,
+    since_secs: u64,
+    ips: HashMap<String, i32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventFilter {
+    /// comma separated ids, all peers if empty
+    #[serde(default)]
+    ids: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TestGeo {
+    /// id of the peer to connect to, the pair key of the relay server
+    #[serde(default)]
+    id: String,
+    a: IpAddr,
+    b: Option<IpAddr>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelayServer {
+    relay_server: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PeerState {
+    id: String,
+    online: bool,
+    last_reg_secs: Option<u64>,
+    socket_addr: Option<String>,
+    ip: String,
+    uuid: String,
+    pk: String,
+    disabled: bool,
+    /// hbba user owning the peer and its organization
+    owner: Option<String>,
+    org: Option<String>,
+}
+
+async fn get_relay_servers(_: Admin, tx: Extension<Sender>) -> Response<RelayServers> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(RelayServers {
+            relay_servers: rs.relay_servers(),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn set_relay_servers(
+    _: Admin,
+    Json(rs): Json<RelayServers>,
+    tx: Extension<Sender>,
+) -> Response<()> {
+    RendezvousServer::set_relay_servers(&tx, &rs.relay_servers.join(","));
+    Response::ok(())
+}
+
+async fn get_relay_loads(_: Admin, tx: Extension<Sender>) -> Response<RelayLoads> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(RelayLoads {
+            relay_loads: rs
+                .relay_loads()
+                .into_iter()
+                .map(|(relay_server, weight, load)| RelayLoad {
+                    relay_server,
+                    weight,
+                    sessions: load.map(|x| x.sessions),
+                    bandwidth: load.map(|x| x.bandwidth),
+                    total_bandwidth: load.map(|x| x.total_bandwidth),
+                })
+                .collect(),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn get_relay_health(_: Admin, tx: Extension<Sender>) -> Response<RelayHealths> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(RelayHealths {
+            relay_health: rs
+                .relay_health()
+                .into_iter()
+                .map(|(relay_server, h)| RelayHealth {
+                    relay_server,
+                    healthy: h.as_ref().map(|x| x.healthy).unwrap_or(true),
+                    failures: h.as_ref().map(|x| x.failures),
+                    successes: h.as_ref().map(|x| x.successes),
+                    latency: h.as_ref().and_then(|x| x.latency),
+                    error: h.and_then(|x| (!x.error.is_empty()).then(|| x.error)),
+                })
+                .collect(),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn get_always_use_relay(_: Admin) -> Response<AlwaysUseRelay> {
+    Response::ok(AlwaysUseRelay {
+        always_use_relay: RendezvousServer::always_use_relay(),
+    })
+}
+
+async fn set_always_use_relay(_: Admin, Json(v): Json<AlwaysUseRelay>) -> Response<()> {
+    RendezvousServer::set_always_use_relay(v.always_use_relay);
+    Response::ok(())
+}
+
+async fn get_ip_blocker(_: Admin, Query(page): Query<Page>) -> Response<List<IpBlocker>> {
+    let mut lock = IP_BLOCKER.lock().await;
+    lock.retain(|&_, (a, b)| {
+        a.1.elapsed().as_secs() <= IP_BLOCK_DUR || b.1.elapsed().as_secs() <= DAY_SECONDS
+    });
+    Response::ok(List {
+        total: lock.len(),
+        items: page.range(lock.iter().map(|(ip, (a, b))| IpBlocker {
+            ip: ip.clone(),
+            requests: a.0,
+            last_request_secs: a.1.elapsed().as_secs(),
+            ids: b.0.len(),
+            last_id_secs: b.1.elapsed().as_secs(),
+        })),
+    })
+}
+
+async fn delete_ip_blocker(_: Admin, Path(ip): Path<String>) -> Response<()> {
+    IP_BLOCKER.lock().await.remove(&ip);
+    Response::ok(())
+}
+
+async fn get_ip_changes(_: Admin, Query(page): Query<Page>) -> Response<List<IpChanges>> {
+    let mut lock = IP_CHANGES.lock().await;
+    lock.retain(|&_, v| v.0.elapsed().as_secs() < IP_CHANGE_DUR_X2 && v.1.len() > 1);
+    Response::ok(List {
+        total: lock.len(),
+        items: page.range(lock.iter().map(|(id, (tm, ips))| IpChanges {
+            id: id.clone(),
+            since_secs: tm.elapsed().as_secs(),
+            ips: ips.clone(),
+        })),
+    })
+}
+
+async fn delete_ip_changes(_: Admin, Path(id): Path<String>) -> Response<()> {
+    IP_CHANGES.lock().await.remove(&id);
+    Response::ok(())
+}
+
+async fn test_geo(
+    _: Admin,
+    Query(q): Query<TestGeo>,
+    tx: Extension<Sender>,
+) -> Response<RelayServer> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(RelayServer {
+            relay_server: rs.get_relay_server(&q.id, q.a, q.b.unwrap_or(q.a)),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn reload_geo(_: Admin, tx: Extension<Sender>) -> Response<()> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => match rs.reload_geo() {
+            Ok(_) => Response::ok(()),
+            Err(err) => Response::error(err),
+        },
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn get_online(
+    _: Admin,
+    Json(q): Json<OnlineQuery>,
+    tx: Extension<Sender>,
+) -> Response<OnlineStates> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) if !rs.check_online_key(&q.licence_key) => Response::error("licence mismatch"),
+        Some(rs) => Response::ok(OnlineStates {
+            states: rs
+                .online_states(&q.ids)
+                .await
+                .into_iter()
+                .map(|x| OnlineState {
+                    id: x.id,
+                    online: x.online,
+                    last_seen: if x.last_seen > 0 {
+                        Some(x.last_seen)
+                    } else {
+                        None
+                    },
+                })
+                .collect(),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+/// Server-sent events of the peers going online, offline or changing ip, a
+/// `lagged` event tells the subscriber it missed some events.
+async fn get_events(
+    _: Admin,
+    Query(filter): Query<EventFilter>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let ids: HashSet<String> = filter
+        .ids
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned())
+        .collect();
+    let stream = stream::unfold((events::subscribe(), ids), |(mut rx, ids)| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(e) => {
+                    if !ids.is_empty() && !ids.contains(&e.id) {
+                        continue;
+                    }
+                    Event::default().event(e.event.as_str()).json_data(&e)
+                }
+                Err(RecvError::Lagged(n)) => {
+                    Ok(Event::default().event("lagged").data(n.to_string()))
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((event, (rx, ids)));
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn get_connection_policy(_: Admin, tx: Extension<Sender>) -> Response<Rules> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(rs.connection_rules().await),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn get_cluster(_: Admin, tx: Extension<Sender>) -> Response<ClusterNodes> {
+    match RendezvousServer::current(&tx).await {
+        Some(rs) => Response::ok(ClusterNodes {
+            nodes: rs
+                .cluster_peers()
+                .await
+                .into_iter()
+                .map(|(node, peers)| ClusterNode {
+                    node: node.to_string(),
+                    peers,
+                })
+                .collect(),
+        }),
+        None => Response::error("server is not running"),
+    }
+}
+
+async fn get_peer(_: Admin, Path(id): Path<String>, tx: Extension<Sender>) -> Response<PeerState> {
+    let rs = match RendezvousServer::current(&tx).await {
+        Some(rs) => rs,
+        None => return Response::error("server is not running"),
+    };
+    let owner = rs.peer_map().db.get_owner(&id).await.ok().flatten();
+    match rs.peer_map().get(&id).await {
+        Some(peer) => {
+            let peer = peer.read().await;
+            let registered = peer.socket_addr.port() != 0;
+            Response::ok(PeerState {
+                online: RendezvousServer::is_online(&peer),
+                last_reg_secs: if registered {
+                    Some(peer.last_reg_time.elapsed().as_secs())
+                } else {
+                    None
+                },
+                socket_addr: if registered {
+                    Some(peer.socket_addr.to_string())
+                } else {
+                    None
+                },
+                ip: peer.info.ip.clone(),
+                uuid: base64::encode(&peer.uuid),
+                pk: base64::encode(&peer.pk),
+                disabled: peer.disabled,
+                owner: owner.as_ref().map(|x| x.0.clone()),
+                org: owner.map(|x| x.1),
+                id,
+            })
+        }
+        None => Response::error("id not exist"),
+    }
+}
+
+async fn set_peer_disabled(
+    _: Admin,
+    Path(id): Path<String>,
+    Json(v): Json<PeerDisabled>,
+    tx: Extension<Sender>,
+) -> Response<()> {
+    let rs = match RendezvousServer::current(&tx).await {
+        Some(rs) => rs,
+        None => return Response::error("server is not running"),
+    };
+    match rs.set_peer_disabled(&id, v.disabled).await {
+        Ok(true) => Response::ok(()),
+        Ok(false) => Response::error("id not exist"),
+        Err(err) => Response::error(err),
+    }
+}
+
+async fn metrics(tx: Extension<Sender>) -> impl IntoResponse {
+    if let Some(rs) = RendezvousServer::current(&tx).await {
+        rs.update_metrics().await;
+    }
+    metrics_response()
+}
@@ -0,0 +1,75 @@
+use hbb_common::log;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_derive::Deserialize;
+
+/// The secret hbba signs its tokens with unless `API_SECRET` is set.
+const DEFAULT_SECRET: &str = "rustdesk api server";
+
+lazy_static::lazy_static! {
+    static ref SECRET: String = std::env::var("API_SECRET")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or(DEFAULT_SECRET.to_owned());
+}
+
+/// Whether `API_SECRET` is set, the default secret is public, so anybody
+/// could sign a token with it.
+pub(crate) fn has_secret() -> bool {
+    SECRET.as_str() != DEFAULT_SECRET
+}
+
+/// The claims of a hbba token used here.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(rename = "iss")]
+    username: String,
+    perm: String,
+}
+
+/// The hbba user a client logged in as, none if the token is missing,
+/// expired, not signed by hbba or not a user token.
+pub(crate) fn get_user(token: &str) -> Option<String> {
+    if token.is_empty() {
+        return None;
+    }
+    let key = DecodingKey::from_secret(SECRET.as_bytes());
+    match decode::<Claims>(token, &key, &Validation::new(Algorithm::HS512)) {
+        Ok(data) if data.claims.perm == "User" => Some(data.claims.username),
+        Ok(_) => None,
+        Err(err) => {
+            log::debug!("Invalid api token: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token(perm: &str, secret: &str, exp: u64) -> String {
+        let claims = serde_json::json!({
+            "iss": "alice",
+            "perm": perm,
+            "exp": exp,
+            "iat": 0,
+            "nbf": 0,
+        });
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::new(super::Algorithm::HS512), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn test_get_user() {
+        let exp = crate::common::now() + 3600;
+        let secret = super::DEFAULT_SECRET;
+        assert_eq!(
+            super::get_user(&token("User", secret, exp)),
+            Some("alice".to_owned())
+        );
+        assert_eq!(super::get_user(&token("Admin", secret, exp)), None);
+        assert_eq!(super::get_user(&token("User", "other", exp)), None);
+        assert_eq!(super::get_user(&token("User", secret, 1)), None);
+        assert_eq!(super::get_user(""), None);
+    }
+}
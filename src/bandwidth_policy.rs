@@ -0,0 +1,105 @@
+use crate::json_file::JsonConfig;
+use hbb_common::log;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Bandwidth classes of relay sessions, keyed by peer id or the hbba user
+/// logged in with the token of the relay request, e.g.
+/// ```json
+/// {
+///   "classes": {
+///     "premium": {"single_bandwidth": 64, "downgrade": false},
+///     "limited": {"single_bandwidth": 2}
+///   },
+///   "ids": {"123456789": "premium"},
+///   "users": {"alice": "premium"},
+///   "default": "limited"
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Policy {
+    #[serde(default)]
+    pub(crate) classes: HashMap<String, Class>,
+    #[serde(default)]
+    pub(crate) ids: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) users: HashMap<String, String>,
+    /// class of the sessions matching none of the ids and users
+    #[serde(default)]
+    pub(crate) default: Option<String>,
+}
+
+/// Overrides of the global relay parameters, bandwidth in Mb/s.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Class {
+    #[serde(default)]
+    pub(crate) single_bandwidth: Option<f64>,
+    /// speed of blacklisted or downgraded sessions
+    #[serde(default)]
+    pub(crate) limit_speed: Option<f64>,
+    /// false to never downgrade the sessions, true if none
+    #[serde(default)]
+    pub(crate) downgrade: Option<bool>,
+}
+
+impl Policy {
+    /// Class of a session, users take precedence over ids, then the default.
+    pub(crate) fn get(&self, ids: &[&str], users: &[&str]) -> Option<(&String, &Class)> {
+        users
+            .iter()
+            .filter(|x| !x.is_empty())
+            .find_map(|x| self.users.get(*x))
+            .or_else(|| {
+                ids.iter()
+                    .filter(|x| !x.is_empty())
+                    .find_map(|x| self.ids.get(*x))
+            })
+            .or(self.default.as_ref())
+            .and_then(|name| self.classes.get_key_value(name))
+    }
+}
+
+impl JsonConfig for Policy {
+    fn prepare(&mut self, file: &str) -> Result<(), String> {
+        for name in self
+            .ids
+            .values()
+            .chain(self.users.values())
+            .chain(self.default.iter())
+        {
+            if !self.classes.contains_key(name) {
+                log::warn!("Unknown bandwidth class {} in {}", name, file);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_get() {
+        let policy: Policy = serde_json::from_str(
+            r#"{
+                "classes": {"a": {}, "b": {}, "c": {}},
+                "ids": {"1": "b", "2": "a"},
+                "users": {"alice": "a", "": "b"},
+                "default": "c"
+            }"#,
+        )
+        .unwrap();
+        let get = |ids: &[&str], users: &[&str]| policy.get(ids, users).map(|x| x.0.as_str());
+        assert_eq!(get(&["1"], &["alice"]), Some("a"));
+        assert_eq!(get(&["1", "2"], &["bob"]), Some("b"));
+        assert_eq!(get(&["", "2"], &["", "bob"]), Some("a"));
+        assert_eq!(get(&["3"], &[""]), Some("c"));
+        assert_eq!(get(&[], &[]), Some("c"));
+        let policy = Policy {
+            default: Some("unknown".to_owned()),
+            ..policy
+        };
+        assert!(policy.get(&["3"], &[]).is_none());
+    }
+}
@@ -12,8 +12,11 @@ fn main() -> ResultType<()> {
     let args = format!(
         "-p, --port=[NUMBER(default={})] 'Sets the listening port'
         -k, --key=[KEY] 'Only allow the client with the same key'
+        --admin-port=[NUMBER(default={})] 'Sets the listening port of the HTTP admin api'
+        --admin-token=[TOKEN] 'Only allow the HTTP admin api requests with the same bearer token'
         ",
         RELAY_PORT,
+        RELAY_PORT + 4,
     );
     let matches = App::new("hbbr")
         .version(hbbs::version::VERSION)
@@ -26,9 +29,24 @@ fn main() -> ResultType<()> {
             section.iter().for_each(|(k, v)| std::env::set_var(k, v));
         }
     }
+    let port = matches
+        .value_of("port")
+        .map(|x| x.to_owned())
+        .unwrap_or(RELAY_PORT.to_string());
+    let admin_port = matches
+        .value_of("admin-port")
+        .map(|x| x.to_owned())
+        .or_else(|| port.parse::<u16>().ok().map(|x| (x + 4).to_string()))
+        .unwrap_or_default();
+    let admin_token = matches
+        .value_of("admin-token")
+        .map(|x| x.to_owned())
+        .unwrap_or(std::env::var("ADMIN_TOKEN").unwrap_or_default());
     start(
-        matches.value_of("port").unwrap_or(&RELAY_PORT.to_string()),
+        &port,
         matches.value_of("key").unwrap_or(""),
+        &admin_port,
+        &admin_token,
     )?;
     Ok(())
 }
@@ -19,11 +19,21 @@ fn main() -> ResultType<()> {
         -s, --serial=[NUMBER(default=0)] 'Sets configure update serial number'
         -R, --rendezvous-servers=[HOSTS] 'Sets rendezvous servers, seperated by colon'
         -u, --software-url=[URL] 'Sets download url of RustDesk software of newest version'
-        -r, --relay-servers=[HOST] 'Sets the default relay servers, seperated by colon'
+        -r, --relay-servers=[HOST] 'Sets the default relay servers, seperated by colon, a server may come with a weight, e.g. host:port@2'
         -M, --rmem=[NUMBER(default={})] 'Sets UDP recv buffer size, set system rmem_max first, e.g., sudo sysctl -w net.core.rmem_max=52428800. vi /etc/sysctl.conf, net.core.rmem_max=52428800, sudo sysctl –p'
-        -k, --key=[KEY] 'Only allow the client with the same key'",
+        -k, --key=[KEY] 'Only allow the client with the same key'
+        --admin-port=[NUMBER(default={})] 'Sets the listening port of the HTTP admin api'
+        --admin-token=[TOKEN] 'Only allow the HTTP admin api requests with the same bearer token'
+        --geoip-db=[FILE] 'Sets the MaxMind GeoIP2/GeoLite2 City database, to choose the relay server nearest to the peers'
+        --peer-snapshot=[FILE(default=./peer_snapshot.json)] 'Sets the file the live peers are saved to on shutdown and restored from on start, - to disable'
+        --connection-policy=[FILE(default=./connection_policy.json)] 'Sets the json file of the rules who may connect to whom, reloaded when edited'
+        --peer-idle-timeout=[SECONDS(default=86400)] 'Sets how long a peer not registering is kept in memory, it is loaded from the database again when needed'
+        --max-peers=[NUMBER(default=0)] 'Sets the maximum number of peers in memory, the least recently registered offline ones are evicted first, 0 for no limit'
+        --cluster-nodes=[HOSTS] 'Sets the other hbbs nodes sharing the peers, host:port of their rendezvous port seperated by comma, the nodes must share a postgres DB_URL'
+        --cluster-secret=[SECRET] 'Only accept the cluster messages with the same secret, required by cluster-nodes'",
         RENDEZVOUS_PORT,
         RMEM,
+        RENDEZVOUS_PORT + 4,
     );
     init_args(&args, "hbbs", "RustDesk ID/Rendezvous Server");
     let port = get_arg_or("port", RENDEZVOUS_PORT.to_string()).parse::<i32>()?;
@@ -0,0 +1,275 @@
+use crate::events::{self, Kind};
+use crate::metrics;
+use crate::peer::PeerMap;
+use crate::rendezvous_server::{Data, Sender, REG_TIMEOUT};
+use hbb_common::{
+    log,
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    tokio::{
+        self,
+        sync::{broadcast::error::RecvError, Mutex, RwLock},
+        time::{interval, Duration},
+    },
+    AddrMangle,
+};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Instant,
+};
+
+const GOSSIP_INTERVAL: u64 = 1_000;
+const SYNC_INTERVAL: u64 = 30_000; // send all the online peers, in case of lost changes
+const PEER_TIMEOUT: u64 = SYNC_INTERVAL * 5 / 2; // a node missing two syncs is lost
+const ROUTE_TIMEOUT: u64 = 30_000;
+const GOSSIP_CHUNK: usize = 200; // peers per udp packet
+
+/// hbbs nodes behind one address, sharing a postgres peer store. A node
+/// tells the others which peers are registered on it, and forwards the
+/// requests to a peer registered on another node to that node, which
+/// sends the responses of the peer back the same way.
+///
+/// The nodes talk over the udp rendezvous port with a shared secret, so
+/// they should be on a private network.
+pub(crate) struct Cluster {
+    nodes: Vec<SocketAddr>,
+    secret: String,
+    /// id -> (node, addr of the peer, updated)
+    peers: RwLock<HashMap<String, (SocketAddr, SocketAddr, Instant)>>,
+    /// requester -> (node forwarded from, updated)
+    routes: Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>,
+    /// ids of the peers disabled or enabled here since the last gossip
+    status_changed: Mutex<Vec<String>>,
+}
+
+impl Cluster {
+    /// `nodes` are the other nodes, host:port seperated by comma, an empty
+    /// one disables clustering.
+    pub(crate) fn new(nodes: &str, secret: &str) -> Self {
+        let mut cluster = Self {
+            nodes: Vec::new(),
+            secret: secret.to_owned(),
+            peers: Default::default(),
+            routes: Default::default(),
+            status_changed: Default::default(),
+        };
+        for node in nodes.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match node.to_socket_addrs().map(|mut x| x.next()) {
+                Ok(Some(addr)) => cluster.nodes.push(addr),
+                _ => log::error!("Invalid cluster node {}", node),
+            }
+        }
+        if !cluster.nodes.is_empty() && secret.is_empty() {
+            log::error!("Clustering disabled without cluster-secret");
+            cluster.nodes.clear();
+        }
+        cluster
+    }
+
+    #[inline]
+    pub(crate) fn enabled(&self) -> bool {
+        !self.nodes.is_empty()
+    }
+
+    #[inline]
+    fn accept(&self, secret: &str, from: SocketAddr) -> bool {
+        let ok = self.enabled() && secret == self.secret && self.nodes.contains(&from);
+        if !ok {
+            log::warn!("Cluster message from {} refused", from);
+        }
+        ok
+    }
+
+    /// The node a peer is registered on and the address of the peer.
+    pub(crate) async fn lookup(&self, id: &str) -> Option<(SocketAddr, SocketAddr)> {
+        if !self.enabled() {
+            return None;
+        }
+        match self.peers.read().await.get(id) {
+            Some((node, addr, tm)) if tm.elapsed().as_millis() < PEER_TIMEOUT as _ => {
+                Some((*node, *addr))
+            }
+            _ => None,
+        }
+    }
+
+    /// (online peers, of which node)
+    pub(crate) async fn peer_counts(&self) -> Vec<(SocketAddr, usize)> {
+        let mut counts: HashMap<SocketAddr, usize> = Default::default();
+        for (node, _, tm) in self.peers.read().await.values() {
+            if tm.elapsed().as_millis() < PEER_TIMEOUT as _ {
+                *counts.entry(*node).or_default() += 1;
+            }
+        }
+        self.nodes
+            .iter()
+            .map(|x| (*x, counts.get(x).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    pub(crate) async fn handle_peers(&self, cp: ClusterPeers, from: SocketAddr, pm: &PeerMap) {
+        if !self.accept(&cp.secret, from) {
+            return;
+        }
+        let now = Instant::now();
+        let mut peers = self.peers.write().await;
+        for p in cp.peers {
+            if p.online {
+                peers.insert(p.id, (from, AddrMangle::decode(&p.socket_addr), now));
+            } else if peers.get(&p.id).map(|x| x.0 == from).unwrap_or(false) {
+                peers.remove(&p.id);
+            }
+        }
+        if !cp.status_changed.is_empty() {
+            let pm = pm.clone();
+            tokio::spawn(async move {
+                for id in cp.status_changed {
+                    if let Err(err) = pm.reload_status(&id).await {
+                        log::error!("Failed to reload the status of {}: {}", id, err);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Tell the other nodes to reload the status of a peer, which is in the
+    /// shared database already.
+    pub(crate) async fn status_changed(&self, id: &str) {
+        if self.enabled() {
+            self.status_changed.lock().await.push(id.to_owned());
+        }
+    }
+
+    /// Wrap a message for another node to send to `to`.
+    pub(crate) fn forward(
+        &self,
+        msg: &RendezvousMessage,
+        to: SocketAddr,
+        requester: Option<SocketAddr>,
+    ) -> RendezvousMessage {
+        metrics::CLUSTER_FORWARDS.with_label_values(&["sent"]).inc();
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_cluster_forward(ClusterForward {
+            secret: self.secret.clone(),
+            to: AddrMangle::encode(to),
+            msg: msg.write_to_bytes().unwrap_or_default(),
+            requester: requester.map(AddrMangle::encode).unwrap_or_default(),
+            ..Default::default()
+        });
+        msg_out
+    }
+
+    /// The message forwarded by another node and where to send it, the
+    /// node of the requester is remembered to send the responses back.
+    pub(crate) async fn handle_forward(
+        &self,
+        cf: ClusterForward,
+        from: SocketAddr,
+    ) -> Option<(RendezvousMessage, SocketAddr)> {
+        if !self.accept(&cf.secret, from) {
+            return None;
+        }
+        metrics::CLUSTER_FORWARDS
+            .with_label_values(&["received"])
+            .inc();
+        if !cf.requester.is_empty() {
+            self.routes
+                .lock()
+                .await
+                .insert(AddrMangle::decode(&cf.requester), (from, Instant::now()));
+        }
+        let msg = RendezvousMessage::parse_from_bytes(&cf.msg).ok()?;
+        Some((msg, AddrMangle::decode(&cf.to)))
+    }
+
+    /// The node a request of `requester` was forwarded from.
+    pub(crate) async fn route(&self, requester: SocketAddr) -> Option<SocketAddr> {
+        if !self.enabled() {
+            return None;
+        }
+        match self.routes.lock().await.get(&requester) {
+            Some((node, tm)) if tm.elapsed().as_millis() < ROUTE_TIMEOUT as _ => Some(*node),
+            _ => None,
+        }
+    }
+
+    async fn expire(&self) {
+        self.peers
+            .write()
+            .await
+            .retain(|_, x| x.2.elapsed().as_millis() < PEER_TIMEOUT as _);
+        self.routes
+            .lock()
+            .await
+            .retain(|_, x| x.1.elapsed().as_millis() < ROUTE_TIMEOUT as _);
+    }
+
+    fn send_peers(&self, cp: ClusterPeers, tx: &Sender) {
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_cluster_peers(ClusterPeers {
+            secret: self.secret.clone(),
+            ..cp
+        });
+        for node in self.nodes.iter() {
+            tx.send(Data::Msg(msg_out.clone(), *node)).ok();
+        }
+    }
+
+    /// Tell the other nodes the changes of the local peers every second,
+    /// and all the online ones every `SYNC_INTERVAL`, with the status
+    /// changes of the last `PEER_TIMEOUT` again.
+    pub(crate) async fn gossip(&self, pm: PeerMap, tx: Sender) {
+        let mut rx = events::subscribe();
+        let mut timer = interval(Duration::from_millis(GOSSIP_INTERVAL));
+        let mut changes = Vec::new();
+        let mut recent_status: Vec<(String, Instant)> = Vec::new();
+        let mut last_sync: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(e) => changes.push(ClusterPeer {
+                        id: e.id,
+                        socket_addr: AddrMangle::encode(e.addr),
+                        online: e.event != Kind::Offline,
+                        ..Default::default()
+                    }),
+                    Err(RecvError::Lagged(_)) => last_sync = None,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = timer.tick() => {
+                    let mut status_changed = std::mem::take(&mut *self.status_changed.lock().await);
+                    recent_status.extend(status_changed.iter().map(|x| (x.clone(), Instant::now())));
+                    if last_sync.map(|x| x.elapsed().as_millis() >= SYNC_INTERVAL as _).unwrap_or(true) {
+                        last_sync = Some(Instant::now());
+                        // after the changes, not to lose the offline ones
+                        changes.extend(pm.online_peers(REG_TIMEOUT).await.into_iter().map(
+                            |(id, addr)| ClusterPeer {
+                                id,
+                                socket_addr: AddrMangle::encode(addr),
+                                online: true,
+                                ..Default::default()
+                            },
+                        ));
+                        recent_status.retain(|x| x.1.elapsed().as_millis() < PEER_TIMEOUT as _);
+                        status_changed = recent_status.iter().map(|x| x.0.clone()).collect();
+                        self.expire().await;
+                    }
+                    for chunk in changes.chunks(GOSSIP_CHUNK) {
+                        self.send_peers(ClusterPeers {
+                            peers: chunk.to_vec(),
+                            ..Default::default()
+                        }, &tx);
+                    }
+                    changes.clear();
+                    for chunk in status_changed.chunks(GOSSIP_CHUNK) {
+                        self.send_peers(ClusterPeers {
+                            status_changed: chunk.to_vec(),
+                            ..Default::default()
+                        }, &tx);
+                    }
+                }
+            }
+        }
+    }
+}
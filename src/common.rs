@@ -9,6 +9,10 @@ use std::{
     time::{Instant, SystemTime},
 };
 
+/// Prefix of the uuid of the relay requests hbbs probes hbbr with, answered
+/// with the relay status instead of waiting for a peer.
+pub(crate) const RELAY_PROBE_PREFIX: &str = "(:probe:)";
+
 pub(crate) fn get_expired_time() -> Instant {
     let now = Instant::now();
     now.checked_sub(std::time::Duration::from_secs(3600))
@@ -94,6 +98,32 @@ pub fn now() -> u64 {
         .unwrap_or_default()
 }
 
+/// Resolve on ctrl-c, or SIGTERM on unix.
+pub(crate) async fn wait_for_signal() {
+    use hbb_common::tokio::{self, signal};
+    #[cfg(unix)]
+    {
+        if let Ok(mut term) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    if signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Modification time and size of a file, to tell if it was edited.
+#[inline]
+pub(crate) fn get_modified(file: &str) -> Option<(SystemTime, u64)> {
+    std::fs::metadata(file)
+        .and_then(|x| Ok((x.modified()?, x.len())))
+        .ok()
+}
+
 pub fn gen_sk() -> (String, Option<sign::SecretKey>) {
     let sk_file = "id_ed25519";
     if let Ok(mut file) = std::fs::File::open(sk_file) {
@@ -0,0 +1,272 @@
+use crate::ip_list::{in_net, parse_net};
+use crate::json_file::JsonConfig;
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use hbb_common::rendezvous_proto::ConnType;
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Who may connect to whom, the first matching rule decides, all
+/// connections are allowed without rules, e.g.
+/// ```json
+/// {
+///   "rules": [
+///     {
+///       "action": "allow",
+///       "ips": ["10.8.0.0/16"],
+///       "ids": ["1234*"],
+///       "conn_types": ["file_transfer"],
+///       "days": ["mon", "tue", "wed", "thu", "fri"],
+///       "hours": "09:00-18:00"
+///     },
+///     {"action": "deny", "ips": ["10.8.0.0/16"]}
+///   ],
+///   "default": "allow"
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Rules {
+    #[serde(default)]
+    pub(crate) rules: Vec<Rule>,
+    /// action if no rule matches
+    #[serde(default)]
+    pub(crate) default: Action,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    Allow,
+    Deny,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+/// A rule matches a request if all its non empty conditions match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Rule {
+    pub(crate) action: Action,
+    /// ip or cidr of the requesting peer
+    #[serde(default)]
+    pub(crate) ips: Vec<String>,
+    /// ids of the peer to connect to, `*` matches any characters
+    #[serde(default)]
+    pub(crate) ids: Vec<String>,
+    /// hbba users the requesting peer logged in as
+    #[serde(default)]
+    pub(crate) users: Vec<String>,
+    /// default, file_transfer, port_forward or rdp
+    #[serde(default)]
+    pub(crate) conn_types: Vec<String>,
+    /// mon to sun, in local time
+    #[serde(default)]
+    pub(crate) days: Vec<String>,
+    /// in local time, e.g. 09:00-18:00, or 22:00-06:00 across midnight
+    #[serde(default)]
+    pub(crate) hours: Option<String>,
+    #[serde(skip)]
+    nets: Vec<(u8, u128)>,
+    #[serde(skip)]
+    weekdays: Vec<Weekday>,
+    #[serde(skip)]
+    time_range: Option<(NaiveTime, NaiveTime)>,
+}
+
+/// A punch hole or relay request to check.
+pub(crate) struct Request<'a> {
+    pub(crate) ip: IpAddr,
+    pub(crate) id: &'a str,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) conn_type: ConnType,
+    pub(crate) time: DateTime<Local>,
+}
+
+impl Rules {
+    #[inline]
+    pub(crate) fn allow(&self, req: &Request) -> bool {
+        self.rules
+            .iter()
+            .find(|x| x.matches(req))
+            .map(|x| x.action)
+            .unwrap_or(self.default)
+            == Action::Allow
+    }
+}
+
+impl Rule {
+    fn prepare(&mut self) -> Result<(), String> {
+        self.nets = self
+            .ips
+            .iter()
+            .map(|x| parse_net(x).ok_or_else(|| format!("invalid ip {}", x)))
+            .collect::<Result<_, _>>()?;
+        self.weekdays = self
+            .days
+            .iter()
+            .map(|x| x.parse().map_err(|_| format!("invalid day {}", x)))
+            .collect::<Result<_, _>>()?;
+        for x in self.conn_types.iter() {
+            if !CONN_TYPES.iter().any(|y| y.0 == x) {
+                return Err(format!("invalid conn type {}", x));
+            }
+        }
+        self.time_range = match &self.hours {
+            Some(hours) => {
+                let parse = |x: &str| NaiveTime::parse_from_str(x.trim(), "%H:%M").ok();
+                match hours.split_once('-') {
+                    Some((a, b)) => Some((
+                        parse(a).ok_or_else(|| format!("invalid hours {}", hours))?,
+                        parse(b).ok_or_else(|| format!("invalid hours {}", hours))?,
+                    )),
+                    None => return Err(format!("invalid hours {}", hours)),
+                }
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        if !self.nets.is_empty() && !self.nets.iter().any(|x| in_net(req.ip, *x)) {
+            return false;
+        }
+        if !self.ids.is_empty() && !self.ids.iter().any(|x| wildcard(x, req.id)) {
+            return false;
+        }
+        if !self.users.is_empty()
+            && !req
+                .user
+                .map(|x| self.users.iter().any(|y| y == x))
+                .unwrap_or(false)
+        {
+            return false;
+        }
+        if !self.conn_types.is_empty() {
+            let name = CONN_TYPES
+                .iter()
+                .find(|x| x.1 == req.conn_type)
+                .map(|x| x.0)
+                .unwrap_or_default();
+            if !self.conn_types.iter().any(|x| x == name) {
+                return false;
+            }
+        }
+        if !self.weekdays.is_empty() && !self.weekdays.contains(&req.time.weekday()) {
+            return false;
+        }
+        if let Some((start, end)) = self.time_range {
+            let t = req.time.time();
+            let within = if start <= end {
+                start <= t && t < end
+            } else {
+                t >= start || t < end
+            };
+            if !within {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+const CONN_TYPES: [(&str, ConnType); 4] = [
+    ("default", ConnType::DEFAULT_CONN),
+    ("file_transfer", ConnType::FILE_TRANSFER),
+    ("port_forward", ConnType::PORT_FORWARD),
+    ("rdp", ConnType::RDP),
+];
+
+/// Whether `s` matches `pattern`, in which `*` matches any characters.
+fn wildcard(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut s = match s.strip_prefix(first) {
+        Some(s) => s,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return s.is_empty(),
+    };
+    for part in parts {
+        match s.find(part) {
+            Some(i) => s = &s[i + part.len()..],
+            None => return false,
+        }
+    }
+    s.ends_with(last)
+}
+
+impl JsonConfig for Rules {
+    fn prepare(&mut self, _file: &str) -> Result<(), String> {
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            rule.prepare()
+                .map_err(|err| format!("rule #{}: {}", i + 1, err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_wildcard() {
+        assert!(wildcard("123", "123"));
+        assert!(!wildcard("123", "1234"));
+        assert!(wildcard("12*", "1234"));
+        assert!(wildcard("*34", "1234"));
+        assert!(wildcard("1*3*", "1234"));
+        assert!(wildcard("*", ""));
+        assert!(!wildcard("12*2", "12"));
+    }
+
+    #[test]
+    fn test_allow() {
+        let mut rules: Rules = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "action": "allow",
+                        "ips": ["10.8.0.0/16"],
+                        "ids": ["1234*"],
+                        "conn_types": ["file_transfer"],
+                        "days": ["mon", "tue", "wed", "thu", "fri"],
+                        "hours": "09:00-18:00"
+                    },
+                    {"action": "deny", "ips": ["10.8.0.0/16"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        rules.prepare("").unwrap();
+        // a monday
+        let time = Local.ymd(2022, 5, 2).and_hms(10, 0, 0);
+        let mut req = Request {
+            ip: "10.8.1.1".parse().unwrap(),
+            id: "123456789",
+            user: None,
+            conn_type: ConnType::FILE_TRANSFER,
+            time,
+        };
+        assert!(rules.allow(&req));
+        req.time = Local.ymd(2022, 5, 2).and_hms(19, 0, 0);
+        assert!(!rules.allow(&req));
+        req.time = Local.ymd(2022, 5, 1).and_hms(10, 0, 0);
+        assert!(!rules.allow(&req));
+        req.time = time;
+        req.conn_type = ConnType::DEFAULT_CONN;
+        assert!(!rules.allow(&req));
+        req.conn_type = ConnType::FILE_TRANSFER;
+        req.id = "555555555";
+        assert!(!rules.allow(&req));
+        req.ip = "192.168.1.1".parse().unwrap();
+        assert!(rules.allow(&req));
+    }
+}
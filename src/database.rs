@@ -1,14 +1,17 @@
+use crate::metrics;
 use async_trait::async_trait;
 use hbb_common::{log, ResultType};
 use sqlx::{
     sqlite::SqliteConnectOptions, ConnectOptions, Connection, Error as SqlxError, SqliteConnection,
 };
-use std::{ops::DerefMut, str::FromStr};
+use std::{ops::DerefMut, str::FromStr, sync::Arc};
+
+use crate::pg_database::PgDatabase;
 
 // pub(crate) type DB = sqlx::Sqlite;
 // pub(crate) type MapValue = serde_json::map::Map<String, Value>;
 // pub(crate) type MapStr = std::collections::HashMap<String, String>;
-type Pool = deadpool::managed::Pool<DbPool>;
+pub(crate) type Pool = deadpool::managed::Pool<DbPool>;
 
 pub struct DbPool {
     url: String,
@@ -31,6 +34,55 @@ impl deadpool::managed::Manager for DbPool {
     }
 }
 
+/// Where the registered peers are kept, a sqlite file or a postgres
+/// database which several hbbs may share.
+#[async_trait]
+pub trait PeerStore: Send + Sync {
+    /// Wait for the running queries.
+    async fn flush(&self) -> ResultType<()>;
+
+    async fn count_peers(&self) -> ResultType<i64>;
+
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>>;
+
+    /// Set the status of a peer, 0 is disabled, false if there is no such
+    /// peer.
+    async fn set_status(&self, id: &str, status: Option<i64>) -> ResultType<bool>;
+
+    /// The hbba user owning the peer and its organization, which is the
+    /// user itself if not set, none if the peer is not owned.
+    async fn get_owner(&self, id: &str) -> ResultType<Option<(String, String)>>;
+
+    /// The organization of a hbba user, none if there is no such user.
+    async fn get_org(&self, username: &str) -> ResultType<Option<String>>;
+
+    /// Whether the users of hbba with their organizations are there, which
+    /// `get_owner` and `get_org` need.
+    async fn has_users(&self) -> ResultType<bool>;
+
+    /// Insert a new peer, returns its guid.
+    async fn insert_peer(
+        &self,
+        id: &str,
+        uuid: &Vec<u8>,
+        pk: &Vec<u8>,
+        info: &str,
+    ) -> ResultType<Vec<u8>>;
+
+    async fn update_pk(&self, guid: &Vec<u8>, id: &str, pk: &Vec<u8>, info: &str)
+        -> ResultType<()>;
+}
+
+/// Open the peer store of `url`, a postgres one if it starts with
+/// `postgres://` or `postgresql://`, otherwise a sqlite file.
+pub(crate) async fn open(url: &str) -> ResultType<Arc<dyn PeerStore>> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(PgDatabase::new(url).await?))
+    } else {
+        Ok(Arc::new(Database::new(url).await?))
+    }
+}
+
 #[derive(Clone)]
 pub struct Database {
     pool: Pool,
@@ -47,23 +99,29 @@ pub struct Peer {
     pub status: Option<i64>,
 }
 
+/// Open a connection pool to the sqlite file `url`, created if not exist.
+pub(crate) async fn new_pool(url: &str) -> ResultType<Pool> {
+    if !std::path::Path::new(url).exists() {
+        std::fs::File::create(url).ok();
+    }
+    let n: usize = std::env::var("MAX_DATABASE_CONNECTIONS")
+        .unwrap_or("1".to_owned())
+        .parse()
+        .unwrap_or(1);
+    log::debug!("MAX_DATABASE_CONNECTIONS={}", n);
+    let pool = Pool::new(
+        DbPool {
+            url: url.to_owned(),
+        },
+        n,
+    );
+    let _ = pool.get().await?; // test
+    Ok(pool)
+}
+
 impl Database {
     pub async fn new(url: &str) -> ResultType<Database> {
-        if !std::path::Path::new(url).exists() {
-            std::fs::File::create(url).ok();
-        }
-        let n: usize = std::env::var("MAX_DATABASE_CONNECTIONS")
-            .unwrap_or("1".to_owned())
-            .parse()
-            .unwrap_or(1);
-        log::debug!("MAX_DATABASE_CONNECTIONS={}", n);
-        let pool = Pool::new(
-            DbPool {
-                url: url.to_owned(),
-            },
-            n,
-        );
-        let _ = pool.get().await?; // test
+        let pool = new_pool(url).await?;
         let db = Database { pool };
         db.create_tables().await?;
         Ok(db)
@@ -93,8 +151,33 @@ impl Database {
         .await?;
         Ok(())
     }
+}
+
+#[async_trait]
+impl PeerStore for Database {
+    /// Wait for the running queries, by taking all the connections of the pool.
+    async fn flush(&self) -> ResultType<()> {
+        let mut conns = Vec::new();
+        for _ in 0..self.pool.status().max_size {
+            conns.push(self.pool.get().await?);
+        }
+        Ok(())
+    }
 
-    pub async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+    async fn count_peers(&self) -> ResultType<i64> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["count_peers"])
+            .start_timer();
+        let (n,): (i64,) = sqlx::query_as("select count(*) from peer")
+            .fetch_one(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(n)
+    }
+
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["get_peer"])
+            .start_timer();
         Ok(sqlx::query_as!(
             Peer,
             "select guid, id, uuid, pk, user, status, info from peer where id = ?",
@@ -104,6 +187,54 @@ impl Database {
         .await?)
     }
 
+    async fn set_status(&self, id: &str, status: Option<i64>) -> ResultType<bool> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["set_status"])
+            .start_timer();
+        let res = sqlx::query("update peer set status = ? where id = ?")
+            .bind(status)
+            .bind(id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// The `user` table belongs to hbba, so hbba must share the database.
+    async fn get_owner(&self, id: &str) -> ResultType<Option<(String, String)>> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["get_owner"])
+            .start_timer();
+        Ok(sqlx::query_as(
+            "select cast(peer.user as text), coalesce(user.org, cast(peer.user as text)) from peer
+            left join user on user.username = cast(peer.user as text) and user.perm = 1
+            where peer.id = ? and peer.user is not null",
+        )
+        .bind(id)
+        .fetch_optional(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    async fn get_org(&self, username: &str) -> ResultType<Option<String>> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["get_org"])
+            .start_timer();
+        let org: Option<(String,)> = sqlx::query_as(
+            "select coalesce(org, username) from user where username = ? and perm = 1",
+        )
+        .bind(username)
+        .fetch_optional(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(org.map(|x| x.0))
+    }
+
+    async fn has_users(&self) -> ResultType<bool> {
+        let (n,): (i64,) =
+            sqlx::query_as("select count(*) from pragma_table_info('user') where name = 'org'")
+                .fetch_one(self.pool.get().await?.deref_mut())
+                .await?;
+        Ok(n > 0)
+    }
+
     // pub async fn get_peer_id(&self, guid: &[u8]) -> ResultType<Option<String>> {
     //     Ok(sqlx::query!("select id from peer where guid = ?", guid)
     //         .fetch_optional(self.pool.get().await?.deref_mut())
@@ -129,13 +260,16 @@ impl Database {
     //     Ok(())
     // }
 
-    pub async fn insert_peer(
+    async fn insert_peer(
         &self,
         id: &str,
         uuid: &Vec<u8>,
         pk: &Vec<u8>,
         info: &str,
     ) -> ResultType<Vec<u8>> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["insert_peer"])
+            .start_timer();
         let guid = uuid::Uuid::new_v4().as_bytes().to_vec();
         sqlx::query!(
             "insert into peer(guid, id, uuid, pk, info) values(?, ?, ?, ?, ?)",
@@ -150,13 +284,16 @@ impl Database {
         Ok(guid)
     }
 
-    pub async fn update_pk(
+    async fn update_pk(
         &self,
         guid: &Vec<u8>,
         id: &str,
         pk: &Vec<u8>,
         info: &str,
     ) -> ResultType<()> {
+        let _timer = metrics::DATABASE_DURATION
+            .with_label_values(&["update_pk"])
+            .start_timer();
         sqlx::query!(
             "update peer set id=?, pk=?, info=? where guid=?",
             id,
@@ -172,7 +309,9 @@ impl Database {
 
 #[cfg(test)]
 mod tests {
+    use super::PeerStore;
     use hbb_common::tokio;
+    use std::ops::DerefMut;
     #[test]
     fn test_insert() {
         insert();
@@ -204,6 +343,26 @@ mod tests {
         }
         hbb_common::futures::future::join_all(jobs).await;
     }
+
+    #[test]
+    fn test_has_users() {
+        has_users();
+    }
+
+    #[tokio::main(flavor = "multi_thread")]
+    async fn has_users() {
+        let file = std::env::temp_dir().join("hbbs_test_has_users.sqlite3");
+        std::fs::remove_file(&file).ok();
+        let db = super::Database::new(file.to_str().unwrap()).await.unwrap();
+        assert!(!db.has_users().await.unwrap());
+        // the user table of hbba
+        sqlx::query("create table user (name varchar(100) not null, org varchar(100))")
+            .execute(db.pool.get().await.unwrap().deref_mut())
+            .await
+            .unwrap();
+        assert!(db.has_users().await.unwrap());
+        std::fs::remove_file(&file).ok();
+    }
 }
 
 // #[inline]
@@ -0,0 +1,61 @@
+use crate::common::now;
+use hbb_common::tokio::sync::broadcast;
+use serde_derive::Serialize;
+use std::net::SocketAddr;
+
+/// Events kept for slow subscribers before they lag.
+const CAPACITY: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref EVENTS: broadcast::Sender<PeerEvent> = broadcast::channel(CAPACITY).0;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kind {
+    Online,
+    Offline,
+    IpChange,
+}
+
+impl Kind {
+    #[inline]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Offline => "offline",
+            Self::IpChange => "ip_change",
+        }
+    }
+}
+
+/// A change of the online status or address of a peer.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PeerEvent {
+    pub(crate) event: Kind,
+    pub(crate) id: String,
+    pub(crate) addr: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) old_addr: Option<SocketAddr>,
+    /// unix secs
+    pub(crate) time: u64,
+}
+
+#[inline]
+pub(crate) fn emit(event: Kind, id: &str, addr: SocketAddr, old_addr: Option<SocketAddr>) {
+    // fails only without subscriber
+    EVENTS
+        .send(PeerEvent {
+            event,
+            id: id.to_owned(),
+            addr,
+            old_addr,
+            time: now(),
+        })
+        .ok();
+}
+
+#[inline]
+pub(crate) fn subscribe() -> broadcast::Receiver<PeerEvent> {
+    EVENTS.subscribe()
+}
@@ -0,0 +1,53 @@
+use hbb_common::{log, ResultType};
+use maxminddb::{geoip2, Reader};
+use std::{net::IpAddr, sync::RwLock};
+
+lazy_static::lazy_static! {
+    static ref READER: RwLock<Option<Reader<Vec<u8>>>> = Default::default();
+}
+
+const EARTH_RADIUS: f64 = 6371.; // in km
+
+/// Load or reload a MaxMind GeoIP2/GeoLite2 City database, the current one
+/// is kept on failure.
+pub(crate) fn load(file: &str) -> ResultType<()> {
+    let reader = Reader::open_readfile(file)?;
+    log::info!(
+        "GeoIP database {} loaded, {} built at {}",
+        file,
+        reader.metadata.database_type,
+        reader.metadata.build_epoch
+    );
+    *READER.write().unwrap() = Some(reader);
+    Ok(())
+}
+
+/// (latitude, longitude) in degrees, none if no database is loaded or the
+/// ip is not found, e.g. a private one.
+pub(crate) fn locate(ip: IpAddr) -> Option<(f64, f64)> {
+    let lock = READER.read().unwrap();
+    let city: geoip2::City = lock.as_ref()?.lookup(ip).ok()?;
+    let location = city.location?;
+    Some((location.latitude?, location.longitude?))
+}
+
+/// Great-circle distance in km.
+pub(crate) fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (a.0.to_radians(), a.1.to_radians());
+    let (lat2, lon2) = (b.0.to_radians(), b.1.to_radians());
+    let h = ((lat2 - lat1) / 2.).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_distance() {
+        let paris = (48.8566, 2.3522);
+        let london = (51.5074, -0.1278);
+        let d = super::distance(paris, london);
+        assert!((d - 344.).abs() < 5., "{}", d);
+        assert_eq!(super::distance(paris, paris), 0.);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::common::{get_modified, now};
+use hbb_common::{log, tokio::sync::RwLock};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    net::IpAddr,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// An ip list backed by a text file, one entry per line:
+/// `<ip or cidr> [until=<unix secs>] [reason]`, e.g. `10.0.0.0/8` or
+/// `2001:db8::/32 until=1700000000 bursty customer`. Expired entries are
+/// purged, changes are written back to the file, and the file is reloaded
+/// when it is edited by others.
+pub(crate) struct IpList {
+    file: &'static str,
+    entries: RwLock<Entries>,
+    modified: Mutex<Option<(SystemTime, u64)>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Entry {
+    /// unix secs, permanent if none
+    pub(crate) until: Option<u64>,
+    pub(crate) reason: String,
+}
+
+impl Entry {
+    pub(crate) fn new(secs: Option<u64>, reason: &str) -> Self {
+        Self {
+            until: secs.map(|x| now() + x),
+            reason: reason.trim().to_owned(),
+        }
+    }
+
+    /// Seconds left before it expires, none if permanent.
+    #[inline]
+    pub(crate) fn remaining(&self) -> Option<u64> {
+        self.until.map(|x| x.saturating_sub(now()))
+    }
+
+    #[inline]
+    fn expired(&self, now: u64) -> bool {
+        self.until.map(|x| x <= now).unwrap_or(false)
+    }
+
+    /// Parse the part of a line following the ip.
+    fn parse(s: &str) -> Self {
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("until=") {
+            let mut it = rest.splitn(2, ' ');
+            if let Some(Ok(until)) = it.next().map(|x| x.parse::<u64>()) {
+                return Self {
+                    until: Some(until),
+                    reason: it.next().unwrap_or_default().trim().to_owned(),
+                };
+            }
+        }
+        Self {
+            until: None,
+            reason: s.to_owned(),
+        }
+    }
+
+    fn to_line(&self, ip: &str) -> String {
+        let mut line = ip.to_owned();
+        if let Some(until) = self.until {
+            line += &format!(" until={}", until);
+        }
+        if !self.reason.is_empty() {
+            line += " ";
+            line += &self.reason;
+        }
+        line
+    }
+}
+
+/// The entries as written, plus the parsed networks grouped by prefix length,
+/// ipv4 is mapped into ipv6 (`::ffff:0:0/96`) so both share one table.
+#[derive(Default)]
+struct Entries {
+    ips: HashMap<String, Entry>,
+    nets: BTreeMap<u8, HashMap<u128, usize>>,
+}
+
+impl Entries {
+    fn insert(&mut self, ip: &str, entry: Entry) {
+        if self.ips.insert(ip.to_owned(), entry).is_none() {
+            if let Some((len, net)) = parse_net(ip) {
+                *self.nets.entry(len).or_default().entry(net).or_default() += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, ip: &str) -> bool {
+        if self.ips.remove(ip).is_none() {
+            return false;
+        }
+        // several entries may share one network, e.g. 10.0.0.1/8 and 10.0.0.0/8
+        if let Some((len, net)) = parse_net(ip) {
+            if let Some(nets) = self.nets.get_mut(&len) {
+                if let Some(n) = nets.get_mut(&net) {
+                    *n -= 1;
+                    if *n == 0 {
+                        nets.remove(&net);
+                    }
+                }
+                if nets.is_empty() {
+                    self.nets.remove(&len);
+                }
+            }
+        }
+        true
+    }
+
+    fn purge(&mut self) -> bool {
+        let now = now();
+        let expired: Vec<String> = self
+            .ips
+            .iter()
+            .filter(|(_, entry)| entry.expired(now))
+            .map(|(ip, _)| ip.clone())
+            .collect();
+        for ip in expired.iter() {
+            self.remove(ip);
+        }
+        !expired.is_empty()
+    }
+
+    fn contains(&self, ip: &str) -> bool {
+        if self.ips.contains_key(ip) {
+            return true;
+        }
+        let bits = match ip.parse::<IpAddr>() {
+            Ok(ip) => to_bits(ip).0,
+            Err(_) => return false,
+        };
+        self.nets
+            .iter()
+            .any(|(len, nets)| nets.contains_key(&mask(bits, *len)))
+    }
+}
+
+impl IpList {
+    pub(crate) fn new(file: &'static str) -> Self {
+        Self {
+            file,
+            entries: Default::default(),
+            modified: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn file(&self) -> &'static str {
+        self.file
+    }
+
+    /// `ip` matches an entry exactly or falls in one of the networks,
+    /// expired entries still count until they are purged.
+    #[inline]
+    pub(crate) async fn contains(&self, ip: &str) -> bool {
+        self.entries.read().await.contains(ip)
+    }
+
+    #[inline]
+    pub(crate) async fn len(&self) -> usize {
+        self.entries.read().await.ips.len()
+    }
+
+    pub(crate) async fn list(&self) -> Vec<(String, Entry)> {
+        let mut ips: Vec<(String, Entry)> = self
+            .entries
+            .read()
+            .await
+            .ips
+            .iter()
+            .map(|(ip, entry)| (ip.clone(), entry.clone()))
+            .collect();
+        ips.sort_by(|a, b| a.0.cmp(&b.0));
+        ips
+    }
+
+    /// Add or replace the entries, all sharing `entry`.
+    pub(crate) async fn add<'a>(&self, ips: impl IntoIterator<Item = &'a str>, entry: Entry) {
+        let mut lock = self.entries.write().await;
+        for ip in ips {
+            if ip.is_empty() {
+                continue;
+            }
+            if ip.contains('/') && parse_net(ip).is_none() {
+                log::warn!("Invalid cidr {} added to {}", ip, self.file);
+            }
+            lock.insert(ip, entry.clone());
+        }
+        self.save(&lock.ips);
+    }
+
+    pub(crate) async fn remove<'a>(&self, ips: impl IntoIterator<Item = &'a str>) {
+        let mut lock = self.entries.write().await;
+        for ip in ips {
+            lock.remove(ip);
+        }
+        self.save(&lock.ips);
+    }
+
+    pub(crate) async fn clear(&self) {
+        let mut lock = self.entries.write().await;
+        *lock = Default::default();
+        self.save(&lock.ips);
+    }
+
+    /// Drop the expired entries, return true if any.
+    pub(crate) async fn purge(&self) -> bool {
+        let mut lock = self.entries.write().await;
+        if lock.purge() {
+            self.save(&lock.ips);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Load the file if it was modified since last load or save.
+    pub(crate) async fn reload(&self) -> bool {
+        let modified = get_modified(self.file);
+        if modified == *self.modified.lock().unwrap() {
+            return false;
+        }
+        let mut lock = self.entries.write().await;
+        *lock = Default::default();
+        if let Ok(contents) = std::fs::read_to_string(self.file) {
+            for x in contents.lines() {
+                let mut it = x.trim().splitn(2, ' ');
+                if let Some(ip) = it.next() {
+                    if !ip.is_empty() {
+                        lock.insert(ip, Entry::parse(it.next().unwrap_or_default()));
+                    }
+                }
+            }
+        }
+        *self.modified.lock().unwrap() = modified;
+        true
+    }
+
+    fn save(&self, ips: &HashMap<String, Entry>) {
+        let mut lines: Vec<String> = ips.iter().map(|(ip, entry)| entry.to_line(ip)).collect();
+        lines.sort();
+        let tmp = format!("{}.tmp", self.file);
+        let res = std::fs::File::create(&tmp)
+            .and_then(|mut f| {
+                for x in lines.iter() {
+                    writeln!(f, "{}", x)?;
+                }
+                f.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, self.file));
+        if let Err(err) = res {
+            log::error!("Failed to save {}: {}", self.file, err);
+        }
+        *self.modified.lock().unwrap() = get_modified(self.file);
+    }
+}
+
+/// Ip as u128 with its own bit length, 32 for ipv4 and 128 for ipv6.
+#[inline]
+fn to_bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(ip.to_ipv6_mapped()), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+#[inline]
+fn mask(bits: u128, len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        bits & (!0u128 << (128 - len))
+    }
+}
+
+/// `ip` or `ip/len` to (prefix length in the ipv6 space, masked network).
+pub(crate) fn parse_net(s: &str) -> Option<(u8, u128)> {
+    let (ip, len) = match s.split_once('/') {
+        Some((ip, len)) => (ip, Some(len.parse::<u8>().ok()?)),
+        None => (s, None),
+    };
+    let (bits, max) = to_bits(ip.parse().ok()?);
+    let len = len.unwrap_or(max);
+    if len > max {
+        return None;
+    }
+    let len = 128 - max + len;
+    Some((len, mask(bits, len)))
+}
+
+/// Whether `ip` is in a network of `parse_net`.
+#[inline]
+pub(crate) fn in_net(ip: IpAddr, net: (u8, u128)) -> bool {
+    mask(to_bits(ip).0, net.0) == net.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entries, Entry};
+
+    #[test]
+    fn test_contains() {
+        let mut entries = Entries::default();
+        for ip in [
+            "1.2.3.4",
+            "10.0.0.0/8",
+            "2001:db8::/32",
+            "fe80::1",
+            "bad/ip",
+        ] {
+            entries.insert(ip, Entry::default());
+        }
+        assert!(entries.contains("1.2.3.4"));
+        assert!(!entries.contains("1.2.3.5"));
+        assert!(entries.contains("10.255.0.1"));
+        assert!(entries.contains("::ffff:10.1.2.3"));
+        assert!(!entries.contains("11.0.0.1"));
+        assert!(entries.contains("2001:db8:1::1"));
+        assert!(!entries.contains("2001:db9::1"));
+        assert!(entries.contains("fe80::1"));
+        assert!(entries.contains("bad/ip"));
+        entries.remove("10.0.0.0/8");
+        assert!(!entries.contains("10.255.0.1"));
+        entries.insert("0.0.0.0/0", Entry::default());
+        assert!(entries.contains("11.0.0.1"));
+        assert!(!entries.contains("2001:db9::1"));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut entries = Entries::default();
+        entries.insert("1.2.3.0/24", Entry::new(Some(0), "burst"));
+        entries.insert("1.2.3.4/24", Entry::new(Some(3600), ""));
+        entries.insert("5.6.7.8", Entry::new(None, " abuse "));
+        assert!(entries.purge());
+        assert!(entries.contains("1.2.3.100"));
+        assert!(!entries.purge());
+        entries.remove("1.2.3.4/24");
+        assert!(!entries.contains("1.2.3.100"));
+        let entry = entries.ips["5.6.7.8"].clone();
+        assert_eq!(entry.remaining(), None);
+        assert_eq!(entry.to_line("5.6.7.8"), "5.6.7.8 abuse");
+        assert_eq!(
+            Entry::parse("until=10 a b"),
+            Entry {
+                until: Some(10),
+                reason: "a b".to_owned()
+            }
+        );
+        assert_eq!(
+            Entry::parse("a b"),
+            Entry {
+                until: None,
+                reason: "a b".to_owned()
+            }
+        );
+    }
+}
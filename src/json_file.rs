@@ -0,0 +1,67 @@
+use crate::common::get_modified;
+use hbb_common::{log, tokio::sync::RwLock};
+use serde::de::DeserializeOwned;
+use std::{sync::Arc, sync::Mutex, time::SystemTime};
+
+/// A config loaded from a json file.
+pub(crate) trait JsonConfig: DeserializeOwned + Default {
+    /// Check the config once parsed, an error keeps the current one.
+    fn prepare(&mut self, _file: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A config backed by a json file, reloaded when the file is edited, the
+/// default config is used without the file.
+pub(crate) struct JsonFile<T> {
+    file: String,
+    value: RwLock<Arc<T>>,
+    modified: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl<T: JsonConfig> JsonFile<T> {
+    pub(crate) fn new(file: &str) -> Self {
+        Self {
+            file: file.to_owned(),
+            value: Default::default(),
+            modified: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn file(&self) -> &str {
+        &self.file
+    }
+
+    #[inline]
+    pub(crate) async fn get(&self) -> Arc<T> {
+        self.value.read().await.clone()
+    }
+
+    /// Load the file if it was modified since last load, the current config
+    /// is kept if the file is invalid.
+    pub(crate) async fn reload(&self) -> bool {
+        let modified = get_modified(&self.file);
+        if modified == *self.modified.lock().unwrap() {
+            return false;
+        }
+        *self.modified.lock().unwrap() = modified;
+        let value = if modified.is_some() {
+            let res = std::fs::read_to_string(&self.file)
+                .map_err(|err| err.to_string())
+                .and_then(|x| serde_json::from_str::<T>(&x).map_err(|err| err.to_string()))
+                .and_then(|mut x| x.prepare(&self.file).map(|_| x));
+            match res {
+                Ok(value) => value,
+                Err(err) => {
+                    log::error!("Failed to load {}: {}", self.file, err);
+                    return false;
+                }
+            }
+        } else {
+            Default::default()
+        };
+        *self.value.write().await = Arc::new(value);
+        true
+    }
+}
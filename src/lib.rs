@@ -1,7 +1,19 @@
 mod rendezvous_server;
 pub use rendezvous_server::*;
 pub mod common;
+mod admin;
+mod api_token;
+mod bandwidth_policy;
+mod cluster;
+mod connection_policy;
 mod database;
+mod events;
+mod geo;
+mod ip_list;
+mod json_file;
+mod metrics;
 mod peer;
+mod pg_database;
 pub mod relay_server;
+mod session_log;
 pub mod version;
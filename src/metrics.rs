@@ -0,0 +1,117 @@
+use prometheus::{
+    register_counter, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Counter, Encoder, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static::lazy_static! {
+    pub(crate) static ref PEER_COUNT: IntGaugeVec = register_int_gauge_vec!(
+        "hbbs_peers",
+        "Number of peers, registered in database, loaded in memory or online",
+        &["state"]
+    )
+    .unwrap();
+    pub(crate) static ref PEER_EVICTIONS: IntCounterVec = register_int_counter_vec!(
+        "hbbs_peer_evictions_total",
+        "Offline peers dropped from memory, idle for too long or over the maximum",
+        &["reason"]
+    )
+    .unwrap();
+    pub(crate) static ref CLUSTER_FORWARDS: IntCounterVec = register_int_counter_vec!(
+        "hbbs_cluster_forwards_total",
+        "Messages forwarded between the nodes of a cluster, sent or received",
+        &["direction"]
+    )
+    .unwrap();
+    pub(crate) static ref PUNCH_HOLE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "hbbs_punch_hole_requests_total",
+        "Punch hole requests by outcome",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref PUNCH_HOLE_DECISIONS: IntCounterVec = register_int_counter_vec!(
+        "hbbs_punch_hole_decisions_total",
+        "How the accepted punch hole requests are served, relay or direct",
+        &["decision"]
+    )
+    .unwrap();
+    pub(crate) static ref REGISTER_PK: IntCounterVec = register_int_counter_vec!(
+        "hbbs_register_pk_total",
+        "Register pk requests by RegisterPkResponse result",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref LICENCE_MISMATCHES: IntCounterVec = register_int_counter_vec!(
+        "hbbs_licence_mismatches_total",
+        "Messages rejected in strict key mode by message type",
+        &["message"]
+    )
+    .unwrap();
+    pub(crate) static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "hbbs_messages_total",
+        "Rendezvous messages received by transport",
+        &["transport"]
+    )
+    .unwrap();
+    pub(crate) static ref DATABASE_DURATION: HistogramVec = register_histogram_vec!(
+        "hbbs_database_duration_seconds",
+        "Latency of database operations",
+        &["op"]
+    )
+    .unwrap();
+}
+
+lazy_static::lazy_static! {
+    pub(crate) static ref RELAYS: IntGaugeVec = register_int_gauge_vec!(
+        "hbbr_relays",
+        "Active relay pairs, raw when both sides are tcp, otherwise ws",
+        &["kind"]
+    )
+    .unwrap();
+    pub(crate) static ref WAITING_REQUESTS: IntGauge = register_int_gauge!(
+        "hbbr_waiting_requests",
+        "Relay requests waiting for their peer"
+    )
+    .unwrap();
+    pub(crate) static ref RELAYED_BYTES: IntCounter = register_int_counter!(
+        "hbbr_relayed_bytes_total",
+        "Bytes relayed in both directions"
+    )
+    .unwrap();
+    pub(crate) static ref LIMITED_SESSIONS: IntCounterVec = register_int_counter_vec!(
+        "hbbr_limited_sessions_total",
+        "Relay sessions throttled to limit-speed, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    pub(crate) static ref LIST_HITS: IntCounterVec = register_int_counter_vec!(
+        "hbbr_list_hits_total",
+        "Connections or sessions matched by the blacklist or blocklist",
+        &["list"]
+    )
+    .unwrap();
+    pub(crate) static ref POLICY_SESSIONS: IntCounterVec = register_int_counter_vec!(
+        "hbbr_policy_sessions_total",
+        "Relay sessions by bandwidth class",
+        &["class"]
+    )
+    .unwrap();
+    pub(crate) static ref LIMITER_WAIT: Counter = register_counter!(
+        "hbbr_limiter_wait_seconds_total",
+        "Time spent waiting on the bandwidth limiters"
+    )
+    .unwrap();
+}
+
+/// Text exposition of all registered metrics, with its content type.
+pub(crate) fn gather() -> (String, String) {
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buf) {
+        hbb_common::log::error!("Failed to encode metrics: {}", err);
+    }
+    (
+        encoder.format_type().to_owned(),
+        String::from_utf8(buf).unwrap_or_default(),
+    )
+}
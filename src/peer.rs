@@ -1,5 +1,7 @@
 use crate::common::*;
 use crate::database;
+use crate::events::{self, Kind};
+use crate::rendezvous_server::REG_TIMEOUT;
 use hbb_common::{
     log,
     rendezvous_proto::*,
@@ -7,7 +9,14 @@ use hbb_common::{
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    collections::HashSet,
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 lazy_static::lazy_static! {
     pub(crate) static ref IP_BLOCKER: Mutex<HashMap<String, ((u32, Instant), (HashSet<String>, Instant))>> = Default::default();
@@ -35,7 +44,7 @@ pub(crate) struct Peer {
     #[allow(unused)]
     pub(crate) user: Option<Vec<u8>>,
     pub(crate) info: PeerInfo,
-    #[allow(unused)]
+    /// can not register, be punched to or relayed to
     pub(crate) disabled: bool,
     pub(crate) reg_pk: (u32, Instant), // how often register_pk
 }
@@ -56,12 +65,52 @@ impl Default for Peer {
     }
 }
 
+impl From<database::Peer> for Peer {
+    fn from(v: database::Peer) -> Self {
+        Self {
+            guid: v.guid,
+            uuid: v.uuid,
+            pk: v.pk,
+            user: v.user,
+            info: serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default(),
+            disabled: v.status == Some(0),
+            ..Default::default()
+        }
+    }
+}
+
 pub(crate) type LockPeer = Arc<RwLock<Peer>>;
 
+/// The live peers written on shutdown, so that a restarted hbbs knows
+/// their last addresses before they register again, the rest is loaded
+/// from the database.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    /// unix ms
+    saved_at: u64,
+    peers: Vec<PeerSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerSnapshot {
+    id: String,
+    socket_addr: SocketAddr,
+    /// ms since the last register when saved
+    elapsed: u64,
+}
+
+#[inline]
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[derive(Clone)]
 pub(crate) struct PeerMap {
     map: Arc<RwLock<HashMap<String, LockPeer>>>,
-    pub(crate) db: database::Database,
+    pub(crate) db: Arc<dyn database::PeerStore>,
 }
 
 impl PeerMap {
@@ -84,11 +133,19 @@ impl PeerMap {
         log::info!("DB_URL={}", db);
         let pm = Self {
             map: Default::default(),
-            db: database::Database::new(&db).await?,
+            db: database::open(&db).await?,
         };
         Ok(pm)
     }
 
+    #[cfg(test)]
+    pub(crate) fn with_db(db: Arc<dyn database::PeerStore>) -> Self {
+        Self {
+            map: Default::default(),
+            db,
+        }
+    }
+
     #[inline]
     pub(crate) async fn update_pk(
         &mut self,
@@ -102,6 +159,12 @@ impl PeerMap {
         log::info!("update_pk {} {:?} {:?} {:?}", id, addr, uuid, pk);
         let (info_str, guid) = {
             let mut w = peer.write().await;
+            let was_online = (w.last_reg_time.elapsed().as_millis() as i32) < REG_TIMEOUT;
+            if !was_online {
+                events::emit(Kind::Online, &id, addr, None);
+            } else if w.socket_addr.ip() != addr.ip() {
+                events::emit(Kind::IpChange, &id, addr, Some(w.socket_addr));
+            }
             w.socket_addr = addr;
             w.uuid = uuid.clone();
             w.pk = pk.clone();
@@ -139,16 +202,7 @@ impl PeerMap {
             return p;
         } else {
             if let Ok(Some(v)) = self.db.get_peer(id).await {
-                let peer = Peer {
-                    guid: v.guid,
-                    uuid: v.uuid,
-                    pk: v.pk,
-                    user: v.user,
-                    info: serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default(),
-                    disabled: v.status == Some(0),
-                    ..Default::default()
-                };
-                let peer = Arc::new(RwLock::new(peer));
+                let peer = Arc::new(RwLock::new(Peer::from(v)));
                 self.map.write().await.insert(id.to_owned(), peer.clone());
                 return Some(peer);
             }
@@ -156,6 +210,50 @@ impl PeerMap {
         None
     }
 
+    /// Disable or enable a registered peer, a disabled one goes offline at
+    /// once, false if there is no such peer.
+    pub(crate) async fn set_disabled(&self, id: &str, disabled: bool) -> ResultType<bool> {
+        if !self
+            .db
+            .set_status(id, if disabled { Some(0) } else { None })
+            .await?
+        {
+            return Ok(false);
+        }
+        if let Some(peer) = self.get(id).await {
+            Self::apply_disabled(&peer, disabled).await;
+        }
+        log::info!(
+            "Peer {} {}",
+            id,
+            if disabled { "disabled" } else { "enabled" }
+        );
+        Ok(true)
+    }
+
+    /// Read the status and owner of a peer in memory from the database
+    /// again, after another node of the cluster changed them.
+    pub(crate) async fn reload_status(&self, id: &str) -> ResultType<()> {
+        if let Some(peer) = self.get_in_memory(id).await {
+            let v = match self.db.get_peer(id).await? {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+            peer.write().await.user = v.user;
+            Self::apply_disabled(&peer, v.status == Some(0)).await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn apply_disabled(peer: &LockPeer, disabled: bool) {
+        let mut w = peer.write().await;
+        w.disabled = disabled;
+        if disabled {
+            w.last_reg_time = get_expired_time();
+        }
+    }
+
     #[inline]
     pub(crate) async fn get_or(&self, id: &str) -> LockPeer {
         if let Some(p) = self.get(id).await {
@@ -175,13 +273,269 @@ impl PeerMap {
         self.map.read().await.get(id).map(|x| x.clone())
     }
 
+    /// The peers in memory of `ids`, with one lock of the map.
+    pub(crate) async fn get_many_in_memory(&self, ids: &[String]) -> Vec<Option<LockPeer>> {
+        let map = self.map.read().await;
+        ids.iter().map(|id| map.get(id).cloned()).collect()
+    }
+
     #[inline]
     pub(crate) async fn is_in_memory(&self, id: &str) -> bool {
         self.map.read().await.contains_key(id)
     }
-    //
-    // #[inline]
-    // pub(crate) async fn remove(&self, id: &str) {
-    //     self.map.write().await.remove(id);
-    // }
+
+    /// (in memory, registered within `timeout` ms), in one pass under the
+    /// lock of the map, a peer being written is counted as online.
+    pub(crate) async fn count(&self, timeout: i32) -> (usize, usize) {
+        let map = self.map.read().await;
+        let online = map
+            .values()
+            .filter(|peer| match peer.try_read() {
+                Ok(peer) => (peer.last_reg_time.elapsed().as_millis() as i32) < timeout,
+                Err(_) => true,
+            })
+            .count();
+        (map.len(), online)
+    }
+
+    /// The ids and addresses of the peers registered within `timeout` ms.
+    pub(crate) async fn online_peers(&self, timeout: i32) -> Vec<(String, SocketAddr)> {
+        let peers: Vec<(String, LockPeer)> = self
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let mut online = Vec::new();
+        for (id, peer) in peers {
+            let peer = peer.read().await;
+            if (peer.last_reg_time.elapsed().as_millis() as i32) < timeout {
+                online.push((id, peer.socket_addr));
+            }
+        }
+        online
+    }
+
+    /// Write the peers registered within the last hour to `file`.
+    pub(crate) async fn save_snapshot(&self, file: &str) -> ResultType<usize> {
+        let peers: Vec<(String, LockPeer)> = self
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let expired = get_expired_time();
+        let mut snapshot = Snapshot {
+            saved_at: now_ms(),
+            ..Default::default()
+        };
+        for (id, peer) in peers {
+            let peer = peer.read().await;
+            if peer.socket_addr.port() == 0 || peer.last_reg_time <= expired {
+                continue;
+            }
+            snapshot.peers.push(PeerSnapshot {
+                id,
+                socket_addr: peer.socket_addr,
+                elapsed: peer.last_reg_time.elapsed().as_millis() as _,
+            });
+        }
+        let tmp = format!("{}.tmp", file);
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(serde_json::to_string(&snapshot)?.as_bytes())?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, file)?;
+        Ok(snapshot.peers.len())
+    }
+
+    /// Restore the addresses of the peers of a snapshot, with the rest from
+    /// the database, skipping the ones already in memory and the ones not
+    /// registered within the last hour.
+    pub(crate) async fn load_snapshot(&self, file: &str) -> ResultType<usize> {
+        let snapshot: Snapshot = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+        let age = now_ms().saturating_sub(snapshot.saved_at);
+        let now = Instant::now();
+        let expired = get_expired_time();
+        let mut n = 0;
+        for p in snapshot.peers {
+            let last_reg_time = match now.checked_sub(Duration::from_millis(p.elapsed + age)) {
+                Some(tm) if tm > expired => tm,
+                _ => continue,
+            };
+            if self.is_in_memory(&p.id).await {
+                continue;
+            }
+            // not in the database if it never sent its pk
+            let peer = self
+                .db
+                .get_peer(&p.id)
+                .await?
+                .map(Peer::from)
+                .unwrap_or_default();
+            if peer.disabled {
+                continue;
+            }
+            let peer = Peer {
+                socket_addr: p.socket_addr,
+                last_reg_time,
+                ..peer
+            };
+            self.map
+                .write()
+                .await
+                .entry(p.id)
+                .or_insert_with(|| Arc::new(RwLock::new(peer)));
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Drop the offline peers not registered within `idle`, and the least
+    /// recently registered offline ones while there are more than `max`
+    /// peers in memory (0 for no limit), returns (idle, over max) evicted.
+    /// Only the address and register time are lost, the rest is in the
+    /// database, a peer not inserted yet is inserted first.
+    pub(crate) async fn evict(&self, idle: Duration, max: usize) -> (usize, usize) {
+        let peers: Vec<(String, LockPeer)> = self
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let total = peers.len();
+        let mut offline = Vec::new();
+        for (id, peer) in peers {
+            let (elapsed, unsaved) = {
+                let peer = peer.read().await;
+                (
+                    peer.last_reg_time.elapsed(),
+                    peer.guid.is_empty() && !peer.pk.is_empty(),
+                )
+            };
+            if elapsed.as_millis() as i32 >= REG_TIMEOUT {
+                offline.push((id, peer, elapsed, unsaved));
+            }
+        }
+        // least recently registered first
+        offline.sort_by(|a, b| b.2.cmp(&a.2));
+        let n_idle = offline.iter().take_while(|x| x.2 >= idle).count();
+        let n = if max > 0 && total > max {
+            n_idle.max((total - max).min(offline.len()))
+        } else {
+            n_idle
+        };
+        offline.truncate(n);
+        let mut ids = Vec::with_capacity(n);
+        for (id, peer, elapsed, unsaved) in offline {
+            if unsaved {
+                let (uuid, pk, info) = {
+                    let peer = peer.read().await;
+                    (
+                        peer.uuid.clone(),
+                        peer.pk.clone(),
+                        serde_json::to_string(&peer.info).unwrap_or_default(),
+                    )
+                };
+                match self.db.insert_peer(&id, &uuid, &pk, &info).await {
+                    Ok(guid) => peer.write().await.guid = guid,
+                    Err(err) => {
+                        log::error!("db.insert_peer failed: {}", err);
+                        continue;
+                    }
+                }
+            }
+            ids.push((id, elapsed >= idle));
+        }
+        let (mut n_idle, mut n_max) = (0, 0);
+        let mut w = self.map.write().await;
+        for (id, is_idle) in ids {
+            // skip the ones in use or registered meanwhile
+            let evictable = match w.get(&id) {
+                Some(peer) => {
+                    Arc::strong_count(peer) == 1
+                        && peer
+                            .try_read()
+                            .map(|x| x.last_reg_time.elapsed().as_millis() as i32 >= REG_TIMEOUT)
+                            .unwrap_or(false)
+                }
+                None => false,
+            };
+            if evictable {
+                w.remove(&id);
+                if is_idle {
+                    n_idle += 1;
+                } else {
+                    n_max += 1;
+                }
+            }
+        }
+        if max > 0 && w.len() > max {
+            log::warn!(
+                "{} peers in memory, more than {} because they are online",
+                w.len(),
+                max
+            );
+        }
+        (n_idle, n_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio;
+
+    #[test]
+    fn test_evict() {
+        evict();
+    }
+
+    #[tokio::main(flavor = "multi_thread")]
+    async fn evict() {
+        let file = std::env::temp_dir().join("hbbs_test_evict.sqlite3");
+        std::fs::remove_file(&file).ok();
+        let db = database::Database::new(file.to_str().unwrap())
+            .await
+            .unwrap();
+        let pm = PeerMap::with_db(Arc::new(db));
+        insert(&pm, "online", 0).await;
+        insert(&pm, "idle", 120).await;
+        insert(&pm, "old1", 50).await;
+        insert(&pm, "old2", 40).await;
+        let idle = Duration::from_secs(60);
+        assert_eq!(pm.evict(idle, 0).await, (1, 0));
+        assert_eq!(ids(&pm).await, ["old1", "old2", "online"]);
+        // the least recently registered first
+        assert_eq!(pm.evict(idle, 2).await, (0, 1));
+        assert_eq!(ids(&pm).await, ["old2", "online"]);
+        // never the online ones, even over the cap
+        insert(&pm, "online2", 0).await;
+        assert_eq!(pm.evict(idle, 1).await, (0, 1));
+        assert_eq!(ids(&pm).await, ["online", "online2"]);
+        assert_eq!(pm.evict(idle, 1).await, (0, 0));
+        assert_eq!(ids(&pm).await, ["online", "online2"]);
+        std::fs::remove_file(&file).ok();
+    }
+
+    async fn insert(pm: &PeerMap, id: &str, secs: u64) {
+        let peer = Peer {
+            // in the database already
+            guid: vec![1],
+            last_reg_time: Instant::now() - Duration::from_secs(secs),
+            ..Default::default()
+        };
+        pm.map
+            .write()
+            .await
+            .insert(id.to_owned(), Arc::new(RwLock::new(peer)));
+    }
+
+    async fn ids(pm: &PeerMap) -> Vec<String> {
+        let mut ids: Vec<String> = pm.map.read().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
 }
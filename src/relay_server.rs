@@ -1,3 +1,9 @@
+use crate::api_token;
+use crate::bandwidth_policy::{Class, Policy};
+use crate::ip_list::{Entry, IpList};
+use crate::json_file::JsonFile;
+use crate::metrics;
+use crate::session_log::{Session, SessionLog};
 use async_speed_limit::Limiter;
 use async_trait::async_trait;
 use hbb_common::{
@@ -14,26 +20,37 @@ use hbb_common::{
         self,
         io::{AsyncReadExt, AsyncWriteExt},
         net::{TcpListener, TcpStream},
-        sync::{Mutex, RwLock},
+        sync::{Mutex, Notify, RwLock},
         time::{interval, Duration},
     },
     ResultType,
 };
+use serde_derive::{Deserialize, Serialize};
 use sodiumoxide::crypto::sign;
 use std::{
-    collections::{HashMap, HashSet},
-    io::prelude::*,
+    collections::HashMap,
     io::Error,
     net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-type Usage = (usize, usize, usize, usize);
+pub(crate) type Usage = (usize, usize, usize, usize); // elapsed(ms), total(bit), highest(kb/s), speed(kb/s)
+
+/// The first side of a relay, waiting for its peer.
+struct Pending {
+    request: RequestRelay,
+    addr: SocketAddr,
+    stream: Box<dyn StreamTrait>,
+}
 
 lazy_static::lazy_static! {
-    static ref PEERS: Mutex<HashMap<String, Box<dyn StreamTrait>>> = Default::default();
-    static ref USAGE: RwLock<HashMap<String, Usage>> = Default::default();
-    static ref BLACKLIST: RwLock<HashSet<String>> = Default::default();
-    static ref BLOCKLIST: RwLock<HashSet<String>> = Default::default();
+    static ref PEERS: Mutex<HashMap<String, Pending>> = Default::default();
+    pub(crate) static ref USAGE: RwLock<HashMap<String, Usage>> = Default::default();
+    pub(crate) static ref BLACKLIST: IpList = IpList::new(BLACKLIST_FILE);
+    pub(crate) static ref BLOCKLIST: IpList = IpList::new(BLOCKLIST_FILE);
+    pub(crate) static ref POLICY: JsonFile<Policy> = JsonFile::new(POLICY_FILE);
+    static ref SESSION_LOG: Mutex<Option<SessionLog>> = Default::default();
+    static ref DRAIN: Notify = Notify::new();
 }
 
 static mut DOWNGRADE_THRESHOLD: f64 = 0.66;
@@ -41,58 +58,143 @@ static mut DOWNGRADE_START_CHECK: usize = 1800_000; // in ms
 static mut LIMIT_SPEED: usize = 4 * 1024 * 1024; // in bit/s
 static mut TOTAL_BANDWIDTH: usize = 1024 * 1024 * 1024; // in bit/s
 static mut SINGLE_BANDWIDTH: usize = 16 * 1024 * 1024; // in bit/s
+static mut DRAIN_TIMEOUT: u64 = 600; // in second
+static mut REQUIRE_TOKEN: bool = false;
+static DRAINING: AtomicBool = AtomicBool::new(false);
 const BLACKLIST_FILE: &'static str = "blacklist.txt";
 const BLOCKLIST_FILE: &'static str = "blocklist.txt";
+const POLICY_FILE: &'static str = "bandwidth_policy.json";
+const LIST_RELOAD_INTERVAL: u64 = 3_000; // in ms
 
 #[tokio::main(flavor = "multi_thread")]
-pub async fn start(port: &str, key: &str) -> ResultType<()> {
+pub async fn start(port: &str, key: &str, admin_port: &str, admin_token: &str) -> ResultType<()> {
     let key = get_server_sk(key);
-    if let Ok(mut file) = std::fs::File::open(BLACKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split("\n") {
-                if let Some(ip) = x.trim().split(' ').nth(0) {
-                    BLACKLIST.write().await.insert(ip.to_owned());
-                }
-            }
-        }
-    }
+    BLACKLIST.reload().await;
     log::info!(
         "#blacklist({}): {}",
-        BLACKLIST_FILE,
-        BLACKLIST.read().await.len()
+        BLACKLIST.file(),
+        BLACKLIST.len().await
     );
-    if let Ok(mut file) = std::fs::File::open(BLOCKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split("\n") {
-                if let Some(ip) = x.trim().split(' ').nth(0) {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
-            }
-        }
-    }
+    BLOCKLIST.reload().await;
     log::info!(
         "#blocklist({}): {}",
-        BLOCKLIST_FILE,
-        BLOCKLIST.read().await.len()
+        BLOCKLIST.file(),
+        BLOCKLIST.len().await
     );
+    POLICY.reload().await;
+    log::info!(
+        "#bandwidth classes({}): {}",
+        POLICY.file(),
+        POLICY.get().await.classes.len()
+    );
+    // "-" to disable the session log
+    let db = std::env::var("RELAY_DB_URL").unwrap_or("./relay_sessions.sqlite3".to_owned());
+    if !db.is_empty() && db != "-" {
+        log::info!("RELAY_DB_URL={}", db);
+        match SessionLog::new(&db).await {
+            Ok(db) => *SESSION_LOG.lock().await = Some(db),
+            Err(err) => log::error!("Failed to open relay session log {}: {}", db, err),
+        }
+    }
+    tokio::spawn(async {
+        // pick up edits made by others, e.g. config management
+        let mut timer = interval(Duration::from_millis(LIST_RELOAD_INTERVAL));
+        loop {
+            timer.tick().await;
+            for (name, list) in [("blacklist", &*BLACKLIST), ("blocklist", &*BLOCKLIST)] {
+                if list.reload().await {
+                    log::info!("#{}({}) reloaded: {}", name, list.file(), list.len().await);
+                }
+                if list.purge().await {
+                    log::info!("#{}({}) purged: {}", name, list.file(), list.len().await);
+                }
+            }
+            if POLICY.reload().await {
+                log::info!(
+                    "#bandwidth classes({}) reloaded: {}",
+                    POLICY.file(),
+                    POLICY.get().await.classes.len()
+                );
+            }
+        }
+    });
     let addr = format!("0.0.0.0:{}", port);
     log::info!("Listening on tcp {}", addr);
     let addr2 = format!("0.0.0.0:{}", port.parse::<u16>().unwrap() + 2);
     log::info!("Listening on websocket {}", addr2);
-    loop {
+    check_params()?;
+    let limiter = <Limiter>::new(unsafe { TOTAL_BANDWIDTH as _ });
+    let admin_addr = format!("0.0.0.0:{}", admin_port);
+    let admin_token = admin_token.to_owned();
+    let admin_router = crate::admin::relay::router(limiter.clone());
+    tokio::spawn(async move {
+        allow_err!(crate::admin::serve(&admin_addr, &admin_token, admin_router).await);
+    });
+    tokio::spawn(async {
+        crate::common::wait_for_signal().await;
+        drain();
+        crate::common::wait_for_signal().await;
+        log::info!("Exit without waiting for the relays");
+        std::process::exit(0);
+    });
+    while !is_draining() {
         log::info!("Start");
         io_loop(
             new_listener(&addr, false).await?,
             new_listener(&addr2, false).await?,
             &key,
+            &limiter,
         )
         .await;
     }
+    wait_relays().await;
+    Ok(())
+}
+
+/// Stop accepting connections and new relay requests, `start` returns once
+/// the running relays end or `DRAIN_TIMEOUT` expires.
+pub(crate) fn drain() {
+    if !DRAINING.swap(true, Ordering::SeqCst) {
+        log::info!("Draining, timeout {}s", unsafe { DRAIN_TIMEOUT });
+        DRAIN.notify_one();
+    }
+}
+
+#[inline]
+pub(crate) fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+async fn wait_relays() {
+    let n = {
+        let mut lock = PEERS.lock().await;
+        let n = lock.len();
+        lock.clear();
+        n
+    };
+    log::info!("Listeners stopped, {} waiting relay requests dropped", n);
+    let deadline = std::time::Instant::now() + Duration::from_secs(unsafe { DRAIN_TIMEOUT });
+    let mut timer = interval(Duration::from_secs(1));
+    let mut tm = std::time::Instant::now();
+    loop {
+        timer.tick().await;
+        let n = USAGE.read().await.len();
+        if n == 0 {
+            log::info!("All relays ended");
+            break;
+        }
+        if std::time::Instant::now() >= deadline {
+            log::warn!("Drain timeout, {} relays cut", n);
+            break;
+        }
+        if tm.elapsed().as_secs() >= 10 {
+            log::info!("Waiting for {} relays", n);
+            tm = std::time::Instant::now();
+        }
+    }
 }
 
-fn check_params() {
+fn check_params() -> ResultType<()> {
     let tmp = std::env::var("DOWNGRADE_THRESHOLD")
         .map(|x| x.parse::<f64>().unwrap_or(0.))
         .unwrap_or(0.);
@@ -134,6 +236,28 @@ fn check_params() {
             TOTAL_BANDWIDTH as f64 / 1024. / 1024.
         )
     };
+    let tmp = std::env::var("DRAIN_TIMEOUT")
+        .map(|x| x.parse::<u64>().unwrap_or(0))
+        .unwrap_or(0);
+    if tmp > 0 {
+        unsafe {
+            DRAIN_TIMEOUT = tmp;
+        }
+    }
+    unsafe { log::info!("DRAIN_TIMEOUT: {}s", DRAIN_TIMEOUT) };
+    if std::env::var("REQUIRE_TOKEN")
+        .unwrap_or_default()
+        .to_uppercase()
+        == "Y"
+    {
+        if !api_token::has_secret() {
+            bail!("REQUIRE_TOKEN requires API_SECRET, the secret shared with hbba");
+        }
+        unsafe {
+            REQUIRE_TOKEN = true;
+        }
+    }
+    unsafe { log::info!("REQUIRE_TOKEN: {}", REQUIRE_TOKEN) };
     let tmp = std::env::var("SINGLE_BANDWIDTH")
         .map(|x| x.parse::<f64>().unwrap_or(0.))
         .unwrap_or(0.);
@@ -148,6 +272,80 @@ fn check_params() {
             SINGLE_BANDWIDTH as f64 / 1024. / 1024.
         )
     };
+    Ok(())
+}
+
+/// Relay parameters, bandwidth in Mb/s and downgrade check start in second,
+/// `None` means keeping the current value when used for update.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Params {
+    #[serde(default)]
+    pub(crate) downgrade_threshold: Option<f64>,
+    #[serde(default)]
+    pub(crate) downgrade_start_check: Option<usize>,
+    #[serde(default)]
+    pub(crate) limit_speed: Option<f64>,
+    #[serde(default)]
+    pub(crate) total_bandwidth: Option<f64>,
+    #[serde(default)]
+    pub(crate) single_bandwidth: Option<f64>,
+}
+
+pub(crate) fn get_params() -> Params {
+    unsafe {
+        Params {
+            downgrade_threshold: Some(DOWNGRADE_THRESHOLD),
+            downgrade_start_check: Some(DOWNGRADE_START_CHECK / 1000),
+            limit_speed: Some(LIMIT_SPEED as f64 / 1024. / 1024.),
+            total_bandwidth: Some(TOTAL_BANDWIDTH as f64 / 1024. / 1024.),
+            single_bandwidth: Some(SINGLE_BANDWIDTH as f64 / 1024. / 1024.),
+        }
+    }
+}
+
+pub(crate) fn set_params(params: &Params, limiter: &Limiter) {
+    unsafe {
+        if let Some(v) = params.downgrade_threshold.filter(|v| *v > 0.) {
+            DOWNGRADE_THRESHOLD = v;
+        }
+        if let Some(v) = params.downgrade_start_check.filter(|v| *v > 0) {
+            DOWNGRADE_START_CHECK = v * 1000;
+        }
+        if let Some(v) = params.limit_speed.filter(|v| *v > 0.) {
+            LIMIT_SPEED = (v * 1024. * 1024.) as _;
+        }
+        if let Some(v) = params.total_bandwidth.filter(|v| *v > 0.) {
+            TOTAL_BANDWIDTH = (v * 1024. * 1024.) as _;
+            limiter.set_speed_limit(TOTAL_BANDWIDTH as _);
+        }
+        if let Some(v) = params.single_bandwidth.filter(|v| *v > 0.) {
+            SINGLE_BANDWIDTH = (v * 1024. * 1024.) as _;
+        }
+    }
+}
+
+/// `[seconds] [reason]` of the list add commands, 0 seconds means permanent.
+fn parse_entry(mut args: Vec<&str>) -> Entry {
+    args.retain(|x| !x.is_empty());
+    let secs = args.first().and_then(|x| x.parse::<u64>().ok());
+    let reason = if secs.is_some() {
+        &args[1..]
+    } else {
+        &args[..]
+    }
+    .join(" ");
+    Entry::new(secs.filter(|x| *x > 0), &reason)
+}
+
+fn entry_line(ip: &str, entry: &Entry) -> String {
+    let mut line = ip.to_owned();
+    if let Some(secs) = entry.remaining() {
+        line += &format!(" ({}s left)", secs);
+    }
+    if !entry.reason.is_empty() {
+        line += &format!(" {}", entry.reason);
+    }
+    line
 }
 
 async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
@@ -156,11 +354,11 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     match fds.next() {
         Some("h") => {
             res = format!(
-                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
-                "blacklist-add(ba) <ip>",
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+                "blacklist-add(ba) <ip or cidr> [seconds] [reason]",
                 "blacklist-remove(br) <ip>",
                 "blacklist(b) <ip>",
-                "blocklist-add(Ba) <ip>",
+                "blocklist-add(Ba) <ip or cidr> [seconds] [reason]",
                 "blocklist-remove(Br) <ip>",
                 "blocklist(B) <ip>",
                 "downgrade-threshold(dt) [value]",
@@ -168,60 +366,58 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 "limit-speed(ls) [value(Mb/s)]",
                 "total-bandwidth(tb) [value(Mb/s)]",
                 "single-bandwidth(sb) [value(Mb/s)]",
+                "bandwidth-class(bc) [id or user]",
+                "drain",
                 "usage(u)"
             )
         }
         Some("blacklist-add" | "ba") => {
             if let Some(ip) = fds.next() {
-                for ip in ip.split("|") {
-                    BLACKLIST.write().await.insert(ip.to_owned());
-                }
+                BLACKLIST
+                    .add(ip.split("|"), parse_entry(fds.collect()))
+                    .await;
             }
         }
         Some("blacklist-remove" | "br") => {
             if let Some(ip) = fds.next() {
                 if ip == "all" {
-                    BLACKLIST.write().await.clear();
+                    BLACKLIST.clear().await;
                 } else {
-                    for ip in ip.split("|") {
-                        BLACKLIST.write().await.remove(ip);
-                    }
+                    BLACKLIST.remove(ip.split("|")).await;
                 }
             }
         }
         Some("blacklist" | "b") => {
             if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLACKLIST.read().await.get(ip).is_some());
+                res = format!("{}\n", BLACKLIST.contains(ip).await);
             } else {
-                for ip in BLACKLIST.read().await.clone().into_iter() {
-                    res += &format!("{}\n", ip);
+                for (ip, entry) in BLACKLIST.list().await.into_iter() {
+                    res += &format!("{}\n", entry_line(&ip, &entry));
                 }
             }
         }
         Some("blocklist-add" | "Ba") => {
             if let Some(ip) = fds.next() {
-                for ip in ip.split("|") {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
+                BLOCKLIST
+                    .add(ip.split("|"), parse_entry(fds.collect()))
+                    .await;
             }
         }
         Some("blocklist-remove" | "Br") => {
             if let Some(ip) = fds.next() {
                 if ip == "all" {
-                    BLOCKLIST.write().await.clear();
+                    BLOCKLIST.clear().await;
                 } else {
-                    for ip in ip.split("|") {
-                        BLOCKLIST.write().await.remove(ip);
-                    }
+                    BLOCKLIST.remove(ip.split("|")).await;
                 }
             }
         }
         Some("blocklist" | "B") => {
             if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLOCKLIST.read().await.get(ip).is_some());
+                res = format!("{}\n", BLOCKLIST.contains(ip).await);
             } else {
-                for ip in BLOCKLIST.read().await.clone().into_iter() {
-                    res += &format!("{}\n", ip);
+                for (ip, entry) in BLOCKLIST.list().await.into_iter() {
+                    res += &format!("{}\n", entry_line(&ip, &entry));
                 }
             }
         }
@@ -301,6 +497,24 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 }
             }
         }
+        Some("bandwidth-class" | "bc") => {
+            let policy = POLICY.get().await;
+            if let Some(x) = fds.next() {
+                if let Some((name, class)) = policy.get(&[x], &[x]) {
+                    res = format!("{} {:?}\n", name, class);
+                }
+            } else {
+                let mut classes: Vec<_> = policy.classes.iter().collect();
+                classes.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, class) in classes {
+                    res += &format!("{} {:?}\n", name, class);
+                }
+            }
+        }
+        Some("drain") => {
+            drain();
+            res = format!("draining, {} relays\n", USAGE.read().await.len());
+        }
         Some("usage" | "u") => {
             let mut tmp: Vec<(String, Usage)> = USAGE
                 .read()
@@ -329,16 +543,39 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     res
 }
 
-async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
-    check_params();
-    let limiter = <Limiter>::new(unsafe { TOTAL_BANDWIDTH as _ });
+/// Load reported to hbbs, which picks the least loaded relay server.
+async fn get_relay_status() -> RendezvousMessage {
+    let (sessions, speed) = {
+        let lock = USAGE.read().await;
+        (lock.len(), lock.values().map(|x| x.3).sum::<usize>())
+    };
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_relay_status(RelayStatus {
+        sessions: sessions as _,
+        bandwidth: speed as f64 * 1000. / 1024. / 1024.,
+        total_bandwidth: unsafe { TOTAL_BANDWIDTH as f64 / 1024. / 1024. },
+        draining: is_draining(),
+        ..Default::default()
+    });
+    msg_out
+}
+
+/// Refresh the gauges which are only computed on scrape.
+pub(crate) async fn update_metrics() {
+    metrics::WAITING_REQUESTS.set(PEERS.lock().await.len() as _);
+}
+
+async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str, limiter: &Limiter) {
     loop {
         tokio::select! {
+            _ = DRAIN.notified() => {
+                break;
+            }
             res = listener.accept() => {
                 match res {
                     Ok((stream, addr))  => {
                         stream.set_nodelay(true).ok();
-                        handle_connection(stream, addr, &limiter, key, false).await;
+                        handle_connection(stream, addr, limiter, key, false).await;
                     }
                     Err(err) => {
                        log::error!("listener.accept failed: {}", err);
@@ -350,7 +587,7 @@ async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
                 match res {
                     Ok((stream, addr))  => {
                         stream.set_nodelay(true).ok();
-                        handle_connection(stream, addr, &limiter, key, true).await;
+                        handle_connection(stream, addr, limiter, key, true).await;
                     }
                     Err(err) => {
                        log::error!("listener2.accept failed: {}", err);
@@ -384,7 +621,8 @@ async fn handle_connection(
         });
         return;
     }
-    if BLOCKLIST.read().await.get(&ip).is_some() {
+    if BLOCKLIST.contains(&ip).await {
+        metrics::LIST_HITS.with_label_values(&["blocklist"]).inc();
         log::info!("{} blocked", ip);
         return;
     }
@@ -424,27 +662,111 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
                 if !key.is_empty() && rf.licence_key != key {
                     return;
                 }
+                if rf.uuid.starts_with(crate::common::RELAY_PROBE_PREFIX) {
+                    if let Ok(bytes) = get_relay_status().await.write_to_bytes() {
+                        allow_err!(stream.send_raw(bytes.into()).await);
+                    }
+                    return;
+                }
+                if is_draining() {
+                    log::info!("Relay request {} from {} rejected, draining", rf.uuid, addr);
+                    return;
+                }
                 if !rf.uuid.is_empty() {
-                    let mut peer = PEERS.lock().await.remove(&rf.uuid);
-                    if let Some(peer) = peer.as_mut() {
+                    let peer = PEERS.lock().await.remove(&rf.uuid);
+                    if let Some(mut peer) = peer {
+                        // usually only the side initiating the session is logged in
+                        let users: Vec<String> = [&rf.token, &peer.request.token]
+                            .iter()
+                            .filter_map(|x| api_token::get_user(x))
+                            .collect();
+                        if unsafe { REQUIRE_TOKEN } {
+                            match users.first() {
+                                Some(user) => {
+                                    log::info!("Relay request {} of user {}", rf.uuid, user)
+                                }
+                                None => {
+                                    log::info!(
+                                        "Relay request {} from {} rejected, no valid token",
+                                        rf.uuid,
+                                        addr
+                                    );
+                                    return;
+                                }
+                            }
+                        }
                         log::info!("Relayrequest {} from {} got paired", rf.uuid, addr);
+                        let policy = POLICY.get().await;
+                        let class = policy.get(
+                            &[rf.id.as_str(), peer.request.id.as_str()],
+                            &users.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+                        );
+                        if let Some((name, _)) = class {
+                            log::info!("Relay of {} uses bandwidth class {}", addr, name);
+                            metrics::POLICY_SESSIONS
+                                .with_label_values(&[name.as_str()])
+                                .inc();
+                        }
+                        let mut session = Session {
+                            uuid: rf.uuid.clone(),
+                            id: if rf.id.is_empty() {
+                                peer.request.id.clone()
+                            } else {
+                                rf.id.clone()
+                            },
+                            addr: addr.to_string(),
+                            peer_addr: peer.addr.to_string(),
+                            class: class.map(|x| x.0.clone()),
+                            started_at: crate::common::now() as _,
+                            ..Default::default()
+                        };
                         let id = format!("{}:{}", addr.ip(), addr.port());
                         USAGE.write().await.insert(id.clone(), Default::default());
-                        if !stream.is_ws() && !peer.is_ws() {
-                            peer.set_raw();
+                        let raw = !stream.is_ws() && !peer.stream.is_ws();
+                        if raw {
+                            peer.stream.set_raw();
                             stream.set_raw();
                             log::info!("Both are raw");
                         }
-                        if let Err(err) = relay(addr, &mut stream, peer, limiter, id.clone()).await
+                        let relays =
+                            metrics::RELAYS.with_label_values(&[if raw { "raw" } else { "ws" }]);
+                        relays.inc();
+                        if let Err(err) = relay(
+                            addr,
+                            &mut stream,
+                            &mut peer.stream,
+                            limiter,
+                            id.clone(),
+                            class.map(|x| x.1),
+                            &mut session,
+                        )
+                        .await
                         {
                             log::info!("Relay of {} closed: {}", addr, err);
+                            session.close_reason = err.to_string();
                         } else {
                             log::info!("Relay of {} closed", addr);
                         }
+                        relays.dec();
+                        session.ended_at = crate::common::now() as _;
+                        let db = SESSION_LOG.lock().await.clone();
+                        if let Some(db) = db {
+                            // before removing the usage, so draining waits for it
+                            if let Err(err) = db.insert(&session).await {
+                                log::error!("Failed to log relay session {}: {}", rf.uuid, err);
+                            }
+                        }
                         USAGE.write().await.remove(&id);
                     } else {
                         log::info!("New relay request {} from {}", rf.uuid, addr);
-                        PEERS.lock().await.insert(rf.uuid.clone(), Box::new(stream));
+                        PEERS.lock().await.insert(
+                            rf.uuid.clone(),
+                            Pending {
+                                request: rf.clone(),
+                                addr,
+                                stream: Box::new(stream),
+                            },
+                        );
                         sleep(30.).await;
                         PEERS.lock().await.remove(&rf.uuid);
                     }
@@ -460,6 +782,8 @@ async fn relay(
     peer: &mut Box<dyn StreamTrait>,
     total_limiter: Limiter,
     id: String,
+    class: Option<&Class>,
+    session: &mut Session,
 ) -> ResultType<()> {
     let ip = addr.ip().to_string();
     let mut tm = std::time::Instant::now();
@@ -469,10 +793,21 @@ async fn relay(
     let mut highest_s = 0;
     let mut downgrade: bool = false;
     let mut blacked: bool = false;
-    let limiter = <Limiter>::new(unsafe { SINGLE_BANDWIDTH as _ });
-    let blacklist_limiter = <Limiter>::new(unsafe { LIMIT_SPEED as _ });
+    let single_bandwidth = class
+        .and_then(|x| x.single_bandwidth)
+        .filter(|x| *x > 0.)
+        .map(|x| (x * 1024. * 1024.) as usize)
+        .unwrap_or(unsafe { SINGLE_BANDWIDTH });
+    let limit_speed = class
+        .and_then(|x| x.limit_speed)
+        .filter(|x| *x > 0.)
+        .map(|x| (x * 1024. * 1024.) as usize)
+        .unwrap_or(unsafe { LIMIT_SPEED });
+    let can_downgrade = class.and_then(|x| x.downgrade).unwrap_or(true);
+    let limiter = <Limiter>::new(single_bandwidth as _);
+    let blacklist_limiter = <Limiter>::new(limit_speed as _);
     let downgrade_threshold =
-        (unsafe { SINGLE_BANDWIDTH as f64 * DOWNGRADE_THRESHOLD } / 1000.) as usize; // in bit/ms
+        (single_bandwidth as f64 * unsafe { DOWNGRADE_THRESHOLD } / 1000.) as usize; // in bit/ms
     let mut timer = interval(Duration::from_secs(3));
     let mut last_recv_time = std::time::Instant::now();
     loop {
@@ -481,18 +816,23 @@ async fn relay(
                 if let Some(Ok(bytes)) = res {
                     last_recv_time = std::time::Instant::now();
                     let nb = bytes.len() * 8;
+                    let wait = std::time::Instant::now();
                     if blacked || downgrade {
                         blacklist_limiter.consume(nb).await;
                     } else {
                         limiter.consume(nb).await;
                     }
                     total_limiter.consume(nb).await;
+                    metrics::LIMITER_WAIT.inc_by(wait.elapsed().as_secs_f64());
+                    metrics::RELAYED_BYTES.inc_by(bytes.len() as _);
                     total += nb;
                     total_s += nb;
+                    session.total_bytes += bytes.len() as i64;
                     if bytes.len() > 0 {
                         stream.send_raw(bytes.into()).await?;
                     }
                 } else {
+                    session.close_reason = "peer closed".to_owned();
                     break;
                 }
             },
@@ -500,18 +840,23 @@ async fn relay(
                 if let Some(Ok(bytes)) = res {
                     last_recv_time = std::time::Instant::now();
                     let nb = bytes.len() * 8;
+                    let wait = std::time::Instant::now();
                     if blacked || downgrade {
                         blacklist_limiter.consume(nb).await;
                     } else {
                         limiter.consume(nb).await;
                     }
                     total_limiter.consume(nb).await;
+                    metrics::LIMITER_WAIT.inc_by(wait.elapsed().as_secs_f64());
+                    metrics::RELAYED_BYTES.inc_by(bytes.len() as _);
                     total += nb;
                     total_s += nb;
+                    session.total_bytes += bytes.len() as i64;
                     if bytes.len() > 0 {
                         peer.send_raw(bytes.into()).await?;
                     }
                 } else {
+                    session.close_reason = "closed".to_owned();
                     break;
                 }
             },
@@ -524,15 +869,26 @@ async fn relay(
 
         let n = tm.elapsed().as_millis() as usize;
         if n >= 1_000 {
-            if BLOCKLIST.read().await.get(&ip).is_some() {
+            if BLOCKLIST.contains(&ip).await {
+                metrics::LIST_HITS.with_label_values(&["blocklist"]).inc();
                 log::info!("{} blocked", ip);
+                session.close_reason = "blocked".to_owned();
                 break;
             }
-            blacked = BLACKLIST.read().await.get(&ip).is_some();
+            let was_blacked = blacked;
+            blacked = BLACKLIST.contains(&ip).await;
+            if blacked && !was_blacked {
+                session.blacklisted = true;
+                metrics::LIST_HITS.with_label_values(&["blacklist"]).inc();
+                metrics::LIMITED_SESSIONS
+                    .with_label_values(&["blacklist"])
+                    .inc();
+            }
             tm = std::time::Instant::now();
             let speed = total_s / (n as usize);
             if speed > highest_s {
                 highest_s = speed;
+                session.highest_speed = speed as _;
             }
             elapsed += n;
             USAGE.write().await.insert(
@@ -540,9 +896,13 @@ async fn relay(
                 (elapsed as _, total as _, highest_s as _, speed as _),
             );
             total_s = 0;
-            if elapsed > unsafe { DOWNGRADE_START_CHECK } && !downgrade {
+            if can_downgrade && elapsed > unsafe { DOWNGRADE_START_CHECK } && !downgrade {
                 if total > elapsed * downgrade_threshold {
                     downgrade = true;
+                    session.downgraded = true;
+                    metrics::LIMITED_SESSIONS
+                        .with_label_values(&["downgrade"])
+                        .inc();
                     log::info!(
                         "Downgrade {}, exceed downgrade threshold {}bit/ms in {}ms",
                         id,
@@ -1,5 +1,6 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
     time::Instant,
@@ -19,7 +20,9 @@ use hbb_common::{
     log,
     protobuf::{Message as _, MessageField},
     rendezvous_proto::{
-        register_pk_response::Result::{TOO_FREQUENT, UUID_MISMATCH},
+        register_pk_response::Result::{
+            ID_DISABLED, LICENSE_MISMATCH, TOO_FREQUENT, UUID_MISMATCH,
+        },
         *,
     },
     tcp::{new_listener, FramedStream},
@@ -28,7 +31,7 @@ use hbb_common::{
         self,
         io::{AsyncReadExt, AsyncWriteExt},
         net::{TcpListener, TcpStream},
-        sync::{mpsc, Mutex},
+        sync::{broadcast, mpsc, oneshot, Mutex},
         time::{interval, Duration},
     },
     tokio_util::codec::Framed,
@@ -36,31 +39,139 @@ use hbb_common::{
     AddrMangle, ResultType,
 };
 
+use crate::api_token;
+use crate::cluster::Cluster;
 use crate::common::*;
+use crate::connection_policy::{Action, Request, Rules};
+use crate::events::{self, Kind};
+use crate::geo;
+use crate::json_file::JsonFile;
+use crate::metrics;
 use crate::peer::*;
 
 const ADDR_127: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
-#[derive(Clone, Debug)]
-enum Data {
+pub(crate) enum Data {
     Msg(RendezvousMessage, SocketAddr),
     RelayServers0(String),
-    RelayServers(RelayServers),
+    RelayServers(Vec<(String, Result<Probe, String>)>),
+    Server(oneshot::Sender<RendezvousServer>),
+    Shutdown,
 }
 
-const REG_TIMEOUT: i32 = 30_000;
+pub(crate) const REG_TIMEOUT: i32 = 30_000;
 type TcpStreamSink = SplitSink<Framed<TcpStream, BytesCodec>, Bytes>;
 type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, tungstenite::Message>;
 enum Sink {
     TcpStream(TcpStreamSink),
     Ws(WsSink),
 }
-type Sender = mpsc::UnboundedSender<Data>;
+pub(crate) type Sender = mpsc::UnboundedSender<Data>;
 type Receiver = mpsc::UnboundedReceiver<Data>;
-static mut ROTATION_RELAY_SERVER: usize = 0;
 type RelayServers = Vec<String>;
 static CHECK_RELAY_TIMEOUT: u64 = 3_000;
+static mut RELAY_SLOW: u64 = 1_000; // in ms, a slower probe counts as failed
+static mut LEGACY_RELAY: bool = false; // accept hbbr not answering the probes
+const RELAY_DOWN_FAILURES: u32 = 3;
+const RELAY_UP_SUCCESSES: u32 = 2;
+const GEO_TOLERANCE: f64 = 500.; // in km, relay servers this much farther than the nearest count as near
+const RELAY_OVERLOAD: f64 = 0.9; // of the total bandwidth, no new sessions above
+const RELAY_LOAD_SLACK: f64 = 0.25; // over its share of the sessions, a relay server is passed over
 static mut ALWAYS_USE_RELAY: bool = false;
+static mut STRICT_KEY: bool = false;
+static mut SAME_ORG: bool = false;
+static mut REQUIRE_TOKEN: bool = false;
+const FLUSH_TIMEOUT: u64 = 5_000;
+const MAX_ONLINE_QUERY: usize = 1_000;
+const POLICY_RELOAD_INTERVAL: u64 = 3_000;
+const PEER_EVICT_INTERVAL: u64 = 60_000;
+const DISABLED_FAILURE: &str = "This device is disabled by the administrator";
+const TOKEN_FAILURE: &str = "Please log in to start a remote session";
+const POLICY_FAILURE: &str = "The connection is not allowed by the server policy";
+const OFFLINE_FAILURE: &str = "Remote desktop is offline";
+
+/// Load reported by a relay server.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RelayLoad {
+    pub(crate) sessions: i32,
+    /// in Mb/s
+    pub(crate) bandwidth: f64,
+    pub(crate) total_bandwidth: f64,
+    pub(crate) draining: bool,
+}
+
+impl RelayLoad {
+    /// Whether the relay server should not get new sessions.
+    #[inline]
+    fn overloaded(&self) -> bool {
+        self.draining
+            || (self.total_bandwidth > 0.
+                && self.bandwidth >= self.total_bandwidth * RELAY_OVERLOAD)
+    }
+}
+
+/// Result of a successful relay server probe.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Probe {
+    /// none if hbbr is too old to report it
+    load: Option<RelayLoad>,
+    /// in ms, of the slower of the tcp and websocket probes
+    latency: u64,
+}
+
+/// Health history of a relay server, it is removed after
+/// `RELAY_DOWN_FAILURES` failed probes in a row and added back after
+/// `RELAY_UP_SUCCESSES` successful ones.
+#[derive(Debug, Clone)]
+pub(crate) struct RelayHealth {
+    pub(crate) healthy: bool,
+    pub(crate) failures: u32,
+    pub(crate) successes: u32,
+    pub(crate) latency: Option<u64>,
+    pub(crate) error: String,
+}
+
+impl Default for RelayHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            failures: 0,
+            successes: 0,
+            latency: None,
+            error: "".to_owned(),
+        }
+    }
+}
+
+impl RelayHealth {
+    /// Record a probe, true if the relay server changed from healthy to
+    /// unhealthy or the reverse.
+    fn update(&mut self, probe: &Result<Probe, String>) -> bool {
+        match probe {
+            Ok(probe) => {
+                self.failures = 0;
+                self.successes += 1;
+                self.latency = Some(probe.latency);
+                self.error.clear();
+                if !self.healthy && self.successes >= RELAY_UP_SUCCESSES {
+                    self.healthy = true;
+                    return true;
+                }
+            }
+            Err(err) => {
+                self.successes = 0;
+                self.failures += 1;
+                self.latency = None;
+                self.error = err.clone();
+                if self.healthy && self.failures >= RELAY_DOWN_FAILURES {
+                    self.healthy = false;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
 
 #[derive(Clone)]
 pub struct RendezvousServer {
@@ -69,14 +180,22 @@ pub struct RendezvousServer {
     tx: Sender,
     relay_servers: Arc<RelayServers>,
     relay_servers0: Arc<RelayServers>,
+    relay_locations: Arc<HashMap<String, (f64, f64)>>,
+    relay_weights: Arc<HashMap<String, f64>>,
+    relay_loads: Arc<HashMap<String, RelayLoad>>,
+    relay_health: Arc<HashMap<String, RelayHealth>>,
+    connection_policy: Arc<JsonFile<Rules>>,
+    cluster: Arc<Cluster>,
     serial: i32,
     rendezvous_servers: Arc<Vec<String>>,
     version: String,
     software_url: String,
     sk: Option<sign::SecretKey>,
+    key: String,
 }
 
 enum LoopFailure {
+    Shutdown,
     UdpSocket,
     Listener3,
     Listener2,
@@ -90,6 +209,13 @@ impl RendezvousServer {
         let addr2 = format!("0.0.0.0:{}", port - 1);
         let addr3 = format!("0.0.0.0:{}", port + 2);
         let pm = PeerMap::new().await?;
+        let snapshot = get_arg_or("peer-snapshot", "./peer_snapshot.json".to_owned());
+        if snapshot != "-" && std::path::Path::new(&snapshot).exists() {
+            match pm.load_snapshot(&snapshot).await {
+                Ok(n) => log::info!("{} peers restored from {}", n, snapshot),
+                Err(err) => log::error!("Failed to load {}: {}", snapshot, err),
+            }
+        }
         log::info!("serial={}", serial);
         let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
         log::info!("Listening on tcp/udp {}", addr);
@@ -108,19 +234,100 @@ impl RendezvousServer {
             tx: tx.clone(),
             relay_servers: Default::default(),
             relay_servers0: Default::default(),
+            relay_locations: Default::default(),
+            relay_weights: Default::default(),
+            relay_loads: Default::default(),
+            relay_health: Default::default(),
+            connection_policy: Arc::new(JsonFile::new(&get_arg_or(
+                "connection-policy",
+                "./connection_policy.json".to_owned(),
+            ))),
+            cluster: Arc::new(Cluster::new(
+                &get_arg("cluster-nodes"),
+                &get_arg("cluster-secret"),
+            )),
             serial,
             rendezvous_servers: Arc::new(rendezvous_servers),
             version,
             software_url,
             sk: None,
+            key: Default::default(),
         };
         let key = rs.get_server_sk(key);
+        rs.key = key.clone();
+        let policy = rs.connection_policy.clone();
+        policy.reload().await;
+        log::info!(
+            "#connection rules({}): {}",
+            policy.file(),
+            policy.get().await.rules.len()
+        );
+        tokio::spawn(async move {
+            let mut timer = interval(Duration::from_millis(POLICY_RELOAD_INTERVAL));
+            loop {
+                timer.tick().await;
+                if policy.reload().await {
+                    log::info!(
+                        "#connection rules({}) reloaded: {}",
+                        policy.file(),
+                        policy.get().await.rules.len()
+                    );
+                }
+            }
+        });
+        tokio::spawn(watch_offline(rs.pm.clone()));
+        if rs.cluster.enabled() {
+            log::info!("cluster-nodes={}", get_arg("cluster-nodes"));
+            let cluster = rs.cluster.clone();
+            let pm = rs.pm.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                cluster.gossip(pm, tx).await;
+            });
+        }
+        let idle = get_arg_or("peer-idle-timeout", DAY_SECONDS.to_string())
+            .parse()
+            .unwrap_or(DAY_SECONDS);
+        let max_peers = get_arg("max-peers").parse().unwrap_or(0);
+        log::info!("peer-idle-timeout={}s, max-peers={}", idle, max_peers);
+        let pm = rs.pm.clone();
+        tokio::spawn(async move {
+            let mut timer = interval(Duration::from_millis(PEER_EVICT_INTERVAL));
+            loop {
+                timer.tick().await;
+                let (n_idle, n_max) = pm.evict(Duration::from_secs(idle), max_peers).await;
+                if n_idle + n_max > 0 {
+                    log::info!("Evicted {} idle peers, {} over max-peers", n_idle, n_max);
+                    metrics::PEER_EVICTIONS
+                        .with_label_values(&["idle"])
+                        .inc_by(n_idle as _);
+                    metrics::PEER_EVICTIONS
+                        .with_label_values(&["max"])
+                        .inc_by(n_max as _);
+                }
+            }
+        });
+        let geoip_db = get_arg("geoip-db");
+        if !geoip_db.is_empty() {
+            if let Err(err) = geo::load(&geoip_db) {
+                log::error!("Failed to load GeoIP database {}: {}", geoip_db, err);
+            }
+        }
         std::env::set_var("PORT_FOR_API", port.to_string());
         rs.parse_relay_servers(&get_arg("relay-servers"));
         // let pm = rs.pm.clone();
         let mut listener = new_listener(&addr, false).await?;
         let mut listener2 = new_listener(&addr2, false).await?;
         let mut listener3 = new_listener(&addr3, false).await?;
+        let admin_addr = format!(
+            "0.0.0.0:{}",
+            get_arg_or("admin-port", (port + 4).to_string())
+        );
+        let admin_token = get_arg("admin-token");
+        let admin_router = crate::admin::rendezvous::router(tx.clone());
+        tokio::spawn(async move {
+            allow_err!(crate::admin::serve(&admin_addr, &admin_token, admin_router).await);
+        });
         let test_addr = std::env::var("TEST_HBBS").unwrap_or_default();
         if std::env::var("ALWAYS_USE_RELAY")
             .unwrap_or_default()
@@ -139,6 +346,74 @@ impl RendezvousServer {
                 "N"
             }
         );
+        if std::env::var("STRICT_KEY")
+            .unwrap_or_default()
+            .to_uppercase()
+            == "Y"
+        {
+            if key.is_empty() {
+                log::warn!("STRICT_KEY has no effect without a key");
+            }
+            unsafe {
+                STRICT_KEY = true;
+            }
+        }
+        log::info!(
+            "STRICT_KEY={}",
+            if unsafe { STRICT_KEY } { "Y" } else { "N" }
+        );
+        if std::env::var("SAME_ORG").unwrap_or_default().to_uppercase() == "Y" {
+            if !api_token::has_secret() {
+                hbb_common::bail!("SAME_ORG requires API_SECRET, the secret shared with hbba");
+            }
+            if !rs.pm.db.has_users().await? {
+                hbb_common::bail!(
+                    "SAME_ORG requires the database of hbba, no hbba users in DB_URL"
+                );
+            }
+            unsafe {
+                SAME_ORG = true;
+            }
+        }
+        log::info!("SAME_ORG={}", if unsafe { SAME_ORG } { "Y" } else { "N" });
+        if std::env::var("REQUIRE_TOKEN")
+            .unwrap_or_default()
+            .to_uppercase()
+            == "Y"
+        {
+            if !api_token::has_secret() {
+                hbb_common::bail!("REQUIRE_TOKEN requires API_SECRET, the secret shared with hbba");
+            }
+            unsafe {
+                REQUIRE_TOKEN = true;
+            }
+        }
+        log::info!(
+            "REQUIRE_TOKEN={}",
+            if unsafe { REQUIRE_TOKEN } { "Y" } else { "N" }
+        );
+        let tmp = std::env::var("RELAY_SLOW")
+            .map(|x| x.parse::<u64>().unwrap_or(0))
+            .unwrap_or(0);
+        if tmp > 0 {
+            unsafe {
+                RELAY_SLOW = tmp;
+            }
+        }
+        log::info!("RELAY_SLOW={}ms", unsafe { RELAY_SLOW });
+        if std::env::var("LEGACY_RELAY")
+            .unwrap_or_default()
+            .to_uppercase()
+            == "Y"
+        {
+            unsafe {
+                LEGACY_RELAY = true;
+            }
+        }
+        log::info!(
+            "LEGACY_RELAY={}",
+            if unsafe { LEGACY_RELAY } { "Y" } else { "N" }
+        );
         if test_addr.to_lowercase() != "no" {
             let test_addr = (if test_addr.is_empty() {
                 addr.replace("0.0.0.0", "127.0.0.1")
@@ -146,10 +421,20 @@ impl RendezvousServer {
                 test_addr
             })
             .parse::<SocketAddr>()?;
+            let key = key.to_owned();
             tokio::spawn(async move {
-                allow_err!(test_hbbs(test_addr).await);
+                allow_err!(test_hbbs(test_addr, &key).await);
             });
         };
+        let tx_signal = tx.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            log::info!("Shutting down");
+            tx_signal.send(Data::Shutdown).ok();
+            wait_for_signal().await;
+            log::info!("Exit without saving");
+            std::process::exit(0);
+        });
         loop {
             log::info!("Start");
             match rs
@@ -163,6 +448,9 @@ impl RendezvousServer {
                 )
                 .await
             {
+                LoopFailure::Shutdown => {
+                    break;
+                }
                 LoopFailure::UdpSocket => {
                     drop(socket);
                     socket = FramedSocket::new_with_buf_size(&addr, rmem).await?;
@@ -181,6 +469,27 @@ impl RendezvousServer {
                 }
             }
         }
+        drop(socket);
+        drop(listener);
+        drop(listener2);
+        drop(listener3);
+        rs.shutdown(&snapshot).await;
+        Ok(())
+    }
+
+    /// Wait for the pending database writes, then save the live peers.
+    async fn shutdown(&self, snapshot: &str) {
+        match timeout(FLUSH_TIMEOUT, self.pm.db.flush()).await {
+            Ok(Ok(())) => log::info!("Database flushed"),
+            Ok(Err(err)) => log::error!("Failed to flush database: {}", err),
+            Err(_) => log::warn!("Database flush timeout"),
+        }
+        if snapshot != "-" {
+            match self.pm.save_snapshot(snapshot).await {
+                Ok(n) => log::info!("{} peers saved to {}", n, snapshot),
+                Err(err) => log::error!("Failed to save {}: {}", snapshot, err),
+            }
+        }
     }
 
     async fn io_loop(
@@ -199,8 +508,9 @@ impl RendezvousServer {
                     if self.relay_servers0.len() > 1 {
                         let rs = self.relay_servers0.clone();
                         let tx = self.tx.clone();
+                        let key = key.to_owned();
                         tokio::spawn(async move {
-                            check_relay_servers(rs, tx).await;
+                            check_relay_servers(rs, tx, key).await;
                         });
                     }
                 }
@@ -208,7 +518,9 @@ impl RendezvousServer {
                     match data {
                         Data::Msg(msg, addr) => { allow_err!(socket.send(&msg, addr).await); }
                         Data::RelayServers0(rs) => { self.parse_relay_servers(&rs); }
-                        Data::RelayServers(rs) => { self.relay_servers = Arc::new(rs); }
+                        Data::RelayServers(rs) => { self.update_relay_servers(rs); }
+                        Data::Server(res) => { res.send(self.clone()).ok(); }
+                        Data::Shutdown => { return LoopFailure::Shutdown; }
                     }
                 }
                 res = socket.next() => {
@@ -232,7 +544,7 @@ impl RendezvousServer {
                     match res {
                         Ok((stream, addr))  => {
                             stream.set_nodelay(true).ok();
-                            self.handle_listener2(stream, addr).await;
+                            self.handle_listener2(stream, addr, key).await;
                         }
                         Err(err) => {
                            log::error!("listener2.accept failed: {}", err);
@@ -277,8 +589,12 @@ impl RendezvousServer {
         key: &str,
     ) -> ResultType<()> {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+            metrics::MESSAGES.with_label_values(&["udp"]).inc();
             match msg_in.union {
                 Some(rendezvous_message::Union::register_peer(rp)) => {
+                    if !check_key("register_peer", &rp.licence_key, key, addr) {
+                        return Ok(());
+                    }
                     // B registered
                     if rp.id.len() > 0 {
                         log::trace!("New peer registered: {:?} {:?}", &rp.id, &addr);
@@ -298,6 +614,9 @@ impl RendezvousServer {
                     if rk.uuid.is_empty() || rk.pk.is_empty() {
                         return Ok(());
                     }
+                    if !check_key("register_pk", &rk.licence_key, key, addr) {
+                        return send_rk_res(socket, addr, LICENSE_MISMATCH).await;
+                    }
                     let id = rk.id;
                     let ip = addr.ip().to_string();
                     if id.len() < 6 {
@@ -308,6 +627,10 @@ impl RendezvousServer {
                     let peer = self.pm.get_or(&id).await;
                     let (changed, ip_changed) = {
                         let peer = peer.read().await;
+                        if peer.disabled {
+                            drop(peer);
+                            return send_rk_res(socket, addr, ID_DISABLED).await;
+                        }
                         if peer.uuid.is_empty() {
                             (true, false)
                         } else {
@@ -374,18 +697,13 @@ impl RendezvousServer {
                     if changed {
                         self.pm.update_pk(id, peer, addr, rk.uuid, rk.pk, ip).await;
                     }
-                    let mut msg_out = RendezvousMessage::new();
-                    msg_out.set_register_pk_response(RegisterPkResponse {
-                        result: register_pk_response::Result::OK.into(),
-                        ..Default::default()
-                    });
-                    socket.send(&msg_out, addr).await?
+                    send_rk_res(socket, addr, register_pk_response::Result::OK).await?
                 }
                 Some(rendezvous_message::Union::punch_hole_request(ph)) => {
-                    if self.pm.is_in_memory(&ph.id).await {
+                    if self.pm.is_in_memory(&ph.id).await && !unsafe { SAME_ORG } {
                         self.handle_udp_punch_hole_request(addr, ph, key).await?;
                     } else {
-                        // not in memory, fetch from db with spawn in case blocking me
+                        // not in memory or checking the owner, fetch from db with spawn in case blocking me
                         let mut me = self.clone();
                         let key = key.to_owned();
                         tokio::spawn(async move {
@@ -399,6 +717,18 @@ impl RendezvousServer {
                 Some(rendezvous_message::Union::local_addr(la)) => {
                     self.handle_local_addr(la, addr, Some(socket)).await?;
                 }
+                Some(rendezvous_message::Union::cluster_peers(cp)) => {
+                    self.cluster.handle_peers(cp, addr, &self.pm).await;
+                }
+                Some(rendezvous_message::Union::cluster_forward(cf)) => {
+                    if let Some((msg, to)) = self.cluster.handle_forward(cf, addr).await {
+                        if self.tcp_punch.lock().await.contains_key(&to) {
+                            self.send_to_tcp(msg, to).await;
+                        } else {
+                            socket.send(&msg, to).await?;
+                        }
+                    }
+                }
                 Some(rendezvous_message::Union::configure_update(mut cu)) => {
                     if addr.ip() == ADDR_127 && cu.serial > self.serial {
                         self.serial = cu.serial;
@@ -444,6 +774,9 @@ impl RendezvousServer {
         ws: bool,
     ) -> bool {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+            metrics::MESSAGES
+                .with_label_values(&[if ws { "ws" } else { "tcp" }])
+                .inc();
             match msg_in.union {
                 Some(rendezvous_message::Union::punch_hole_request(ph)) => {
                     // there maybe several attempt, so sink can be none
@@ -454,15 +787,29 @@ impl RendezvousServer {
                     return true;
                 }
                 Some(rendezvous_message::Union::request_relay(mut rf)) => {
+                    let (via, peer_addr) = match self.check_relay_request(addr, &rf, key).await {
+                        Ok(x) => x,
+                        Err(reason) => {
+                            let mut msg_out = RendezvousMessage::new();
+                            msg_out.set_relay_response(RelayResponse {
+                                refuse_reason: reason.to_owned(),
+                                ..Default::default()
+                            });
+                            Self::send_to_sink(sink, msg_out).await;
+                            return false;
+                        }
+                    };
                     // there maybe several attempt, so sink can be none
                     if let Some(sink) = sink.take() {
                         self.tcp_punch.lock().await.insert(addr, sink);
                     }
-                    if let Some(peer) = self.pm.get_in_memory(&rf.id).await {
-                        let mut msg_out = RendezvousMessage::new();
-                        rf.socket_addr = AddrMangle::encode(addr);
-                        msg_out.set_request_relay(rf);
-                        let peer_addr = peer.read().await.socket_addr;
+                    let mut msg_out = RendezvousMessage::new();
+                    rf.socket_addr = AddrMangle::encode(addr);
+                    msg_out.set_request_relay(rf);
+                    if let Some(node) = via {
+                        let msg_out = self.cluster.forward(&msg_out, peer_addr, Some(addr));
+                        self.tx.send(Data::Msg(msg_out, node)).ok();
+                    } else {
                         self.tx.send(Data::Msg(msg_out, peer_addr)).ok();
                     }
                     return true;
@@ -477,7 +824,24 @@ impl RendezvousServer {
                     }
                     let mut msg_out = RendezvousMessage::new();
                     msg_out.set_relay_response(rr);
-                    allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
+                    if let Some(node) = self.cluster.route(addr_b).await {
+                        let msg_out = self.cluster.forward(&msg_out, addr_b, None);
+                        self.tx.send(Data::Msg(msg_out, node)).ok();
+                    } else {
+                        allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
+                    }
+                }
+                Some(rendezvous_message::Union::online_request(or)) => {
+                    // not to tell who is online to those refused a punch hole
+                    if !match_key("online_request", &or.licence_key, key, addr) {
+                        return false;
+                    }
+                    let mut msg_out = RendezvousMessage::new();
+                    msg_out.set_online_response(OnlineResponse {
+                        states: self.online_states(&or.peers).await,
+                        ..Default::default()
+                    });
+                    Self::send_to_sink(sink, msg_out).await;
                 }
                 Some(rendezvous_message::Union::punch_hole_sent(phs)) => {
                     allow_err!(self.handle_hole_sent(phs, addr, None).await);
@@ -486,6 +850,9 @@ impl RendezvousServer {
                     allow_err!(self.handle_local_addr(la, addr, None).await);
                 }
                 Some(rendezvous_message::Union::test_nat_request(tar)) => {
+                    if !check_key("test_nat_request", &tar.licence_key, key, addr) {
+                        return false;
+                    }
                     let mut msg_out = RendezvousMessage::new();
                     let mut res = TestNatResponse {
                         port: addr.port() as _,
@@ -501,10 +868,13 @@ impl RendezvousServer {
                     Self::send_to_sink(sink, msg_out).await;
                 }
                 Some(rendezvous_message::Union::register_pk(rk)) => {
-                    let res = if self.pm.get(&rk.id).await.is_some() {
+                    let res = if !check_key("register_pk", &rk.licence_key, key, addr) {
+                        LICENSE_MISMATCH
+                    } else if self.pm.get(&rk.id).await.is_some() {
                         register_pk_response::Result::ID_EXISTS
                     } else {
                         match self.pm.get(&rk.old_id).await {
+                            Some(peer) if peer.read().await.disabled => ID_DISABLED,
                             Some(peer) => {
                                 let pk = peer.read().await.pk.clone();
                                 self.pm
@@ -521,6 +891,9 @@ impl RendezvousServer {
                             None => register_pk_response::Result::SERVER_ERROR,
                         }
                     };
+                    metrics::REGISTER_PK
+                        .with_label_values(&[&format!("{:?}", res).to_lowercase()])
+                        .inc();
                     let mut msg_out = RendezvousMessage::new();
                     msg_out.set_register_pk_response(RegisterPkResponse {
                         result: res.into(),
@@ -543,6 +916,10 @@ impl RendezvousServer {
     ) -> ResultType<()> {
         let (request_pk, ip_change) = if let Some(old) = self.pm.get_in_memory(&id).await {
             let mut old = old.write().await;
+            if old.disabled {
+                log::debug!("Disabled peer {} can not register from {}", id, socket_addr);
+                return Ok(());
+            }
             let ip = socket_addr.ip();
             let ip_change = if old.socket_addr.port() != 0 {
                 ip != old.socket_addr.ip()
@@ -551,6 +928,9 @@ impl RendezvousServer {
             } && ip != ADDR_127;
             let request_pk = old.pk.is_empty() || ip_change;
             if !request_pk {
+                if !Self::is_online(&old) {
+                    events::emit(Kind::Online, &id, socket_addr, None);
+                }
                 old.socket_addr = socket_addr;
                 old.last_reg_time = Instant::now();
             }
@@ -604,10 +984,25 @@ impl RendezvousServer {
             p.set_nat_type(t);
         }
         msg_out.set_punch_hole_response(p);
-        if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
+        self.send_to_requester(msg_out, addr_a, socket).await
+    }
+
+    /// Send a response to the requester, by the node it came from if it
+    /// was forwarded by another node of the cluster.
+    #[inline]
+    async fn send_to_requester<'a>(
+        &mut self,
+        msg: RendezvousMessage,
+        addr: SocketAddr,
+        socket: Option<&'a mut FramedSocket>,
+    ) -> ResultType<()> {
+        if let Some(node) = self.cluster.route(addr).await {
+            let msg_out = self.cluster.forward(&msg, addr, None);
+            self.tx.send(Data::Msg(msg_out, node))?;
+        } else if let Some(socket) = socket {
+            socket.send(&msg, addr).await?;
         } else {
-            self.send_to_tcp(msg_out, addr_a).await;
+            self.send_to_tcp(msg, addr).await;
         }
         Ok(())
     }
@@ -636,12 +1031,7 @@ impl RendezvousServer {
         };
         p.set_is_local(true);
         msg_out.set_punch_hole_response(p);
-        if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
-        } else {
-            self.send_to_tcp(msg_out, addr_a).await;
-        }
-        Ok(())
+        self.send_to_requester(msg_out, addr_a, socket).await
     }
 
     #[inline]
@@ -653,6 +1043,9 @@ impl RendezvousServer {
         ws: bool,
     ) -> ResultType<(RendezvousMessage, Option<SocketAddr>)> {
         if !key.is_empty() && ph.licence_key != key {
+            metrics::PUNCH_HOLE_REQUESTS
+                .with_label_values(&["license_mismatch"])
+                .inc();
             let mut msg_out = RendezvousMessage::new();
             msg_out.set_punch_hole_response(PunchHoleResponse {
                 failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
@@ -660,6 +1053,45 @@ impl RendezvousServer {
             });
             return Ok((msg_out, None));
         }
+        if !check_token(&ph.token) {
+            metrics::PUNCH_HOLE_REQUESTS
+                .with_label_values(&["token_invalid"])
+                .inc();
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: TOKEN_FAILURE.to_owned(),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
+        if !self
+            .check_policy(addr, &ph.id, &ph.token, ph.conn_type.enum_value())
+            .await
+        {
+            metrics::PUNCH_HOLE_REQUESTS
+                .with_label_values(&["policy_denied"])
+                .inc();
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: POLICY_FAILURE.to_owned(),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
+        // before telling if the peer exists or is online
+        if unsafe { SAME_ORG } {
+            if let Err(reason) = self.check_org(&ph.token, &ph.id).await {
+                metrics::PUNCH_HOLE_REQUESTS
+                    .with_label_values(&["org_mismatch"])
+                    .inc();
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    other_failure: reason.to_owned(),
+                    ..Default::default()
+                });
+                return Ok((msg_out, None));
+            }
+        }
         let id = ph.id;
         // punch hole request from A, relay to B,
         // check if in same intranet first,
@@ -667,11 +1099,37 @@ impl RendezvousServer {
         // because punch hole won't work if in the same intranet,
         // all routers will drop such self-connections.
         if let Some(peer) = self.pm.get(&id).await {
-            let (elapsed, peer_addr) = {
+            let (elapsed, mut peer_addr, disabled) = {
                 let r = peer.read().await;
-                (r.last_reg_time.elapsed().as_millis() as i32, r.socket_addr)
+                (
+                    r.last_reg_time.elapsed().as_millis() as i32,
+                    r.socket_addr,
+                    r.disabled,
+                )
             };
+            if disabled {
+                metrics::PUNCH_HOLE_REQUESTS
+                    .with_label_values(&["disabled"])
+                    .inc();
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    other_failure: DISABLED_FAILURE.to_owned(),
+                    ..Default::default()
+                });
+                return Ok((msg_out, None));
+            }
+            // registered on another node of the cluster
+            let mut via = None;
             if elapsed >= REG_TIMEOUT {
+                if let Some((node, addr)) = self.cluster.lookup(&id).await {
+                    via = Some(node);
+                    peer_addr = addr;
+                }
+            }
+            if elapsed >= REG_TIMEOUT && via.is_none() {
+                metrics::PUNCH_HOLE_REQUESTS
+                    .with_label_values(&["offline"])
+                    .inc();
                 let mut msg_out = RendezvousMessage::new();
                 msg_out.set_punch_hole_response(PunchHoleResponse {
                     failure: punch_hole_response::Failure::OFFLINE.into(),
@@ -679,15 +1137,21 @@ impl RendezvousServer {
                 });
                 return Ok((msg_out, None));
             }
+            metrics::PUNCH_HOLE_REQUESTS
+                .with_label_values(&["ok"])
+                .inc();
             let mut msg_out = RendezvousMessage::new();
             if unsafe { ALWAYS_USE_RELAY } {
-                let relay_server = self.get_relay_server(addr.ip(), peer_addr.ip());
+                let relay_server = self.get_relay_server(&id, addr.ip(), peer_addr.ip());
                 if !relay_server.is_empty() {
+                    metrics::PUNCH_HOLE_DECISIONS
+                        .with_label_values(&["relay"])
+                        .inc();
                     msg_out.set_request_relay(RequestRelay {
                         relay_server,
                         ..Default::default()
                     });
-                    return Ok((msg_out, Some(peer_addr)));
+                    return Ok(self.to_peer(msg_out, peer_addr, via, addr));
                 }
             }
             let same_intranet = !ws
@@ -702,8 +1166,11 @@ impl RendezvousServer {
                     },
                 };
             let socket_addr = AddrMangle::encode(addr);
-            let relay_server = self.get_relay_server(addr.ip(), peer_addr.ip());
+            let relay_server = self.get_relay_server(&id, addr.ip(), peer_addr.ip());
             if same_intranet {
+                metrics::PUNCH_HOLE_DECISIONS
+                    .with_label_values(&["fetch_local_addr"])
+                    .inc();
                 log::debug!(
                     "Fetch local addr {:?} {:?} request from {:?}",
                     id,
@@ -716,6 +1183,9 @@ impl RendezvousServer {
                     ..Default::default()
                 });
             } else {
+                metrics::PUNCH_HOLE_DECISIONS
+                    .with_label_values(&["punch_hole"])
+                    .inc();
                 log::debug!(
                     "Punch hole {:?} {:?} request from {:?}",
                     id,
@@ -729,8 +1199,11 @@ impl RendezvousServer {
                     ..Default::default()
                 });
             }
-            return Ok((msg_out, Some(peer_addr)));
+            return Ok(self.to_peer(msg_out, peer_addr, via, addr));
         } else {
+            metrics::PUNCH_HOLE_REQUESTS
+                .with_label_values(&["id_not_exist"])
+                .inc();
             let mut msg_out = RendezvousMessage::new();
             msg_out.set_punch_hole_response(PunchHoleResponse {
                 failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
@@ -740,6 +1213,25 @@ impl RendezvousServer {
         }
     }
 
+    /// The message to the peer and where to send it, wrapped for the node
+    /// `via` if the peer is registered on another node.
+    #[inline]
+    fn to_peer(
+        &self,
+        msg: RendezvousMessage,
+        peer_addr: SocketAddr,
+        via: Option<SocketAddr>,
+        requester: SocketAddr,
+    ) -> (RendezvousMessage, Option<SocketAddr>) {
+        match via {
+            Some(node) => (
+                self.cluster.forward(&msg, peer_addr, Some(requester)),
+                Some(node),
+            ),
+            None => (msg, Some(peer_addr)),
+        }
+    }
+
     #[inline]
     async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
         let mut tcp = self.tcp_punch.lock().await.remove(&addr);
@@ -792,6 +1284,107 @@ impl RendezvousServer {
         Ok(())
     }
 
+    /// Where to send a tcp relay request, (node of the cluster, address of
+    /// the peer), or why the request is refused, checked before the
+    /// requester waits for the response.
+    async fn check_relay_request(
+        &self,
+        addr: SocketAddr,
+        rf: &RequestRelay,
+        key: &str,
+    ) -> Result<(Option<SocketAddr>, SocketAddr), &'static str> {
+        if !check_key("request_relay", &rf.licence_key, key, addr) {
+            return Err("Licence mismatch");
+        }
+        if !check_token(&rf.token) {
+            return Err(TOKEN_FAILURE);
+        }
+        if !self
+            .check_policy(addr, &rf.id, &rf.token, rf.conn_type.enum_value())
+            .await
+        {
+            return Err(POLICY_FAILURE);
+        }
+        if unsafe { SAME_ORG } {
+            self.check_org(&rf.token, &rf.id).await?;
+        }
+        let peer = match self.pm.get_in_memory(&rf.id).await {
+            Some(peer) => peer,
+            // registered on another node of the cluster only
+            None => {
+                return match self.cluster.lookup(&rf.id).await {
+                    Some((node, peer_addr)) => Ok((Some(node), peer_addr)),
+                    None => Err(OFFLINE_FAILURE),
+                }
+            }
+        };
+        let (online, peer_addr, disabled) = {
+            let peer = peer.read().await;
+            (Self::is_online(&peer), peer.socket_addr, peer.disabled)
+        };
+        if disabled {
+            return Err(DISABLED_FAILURE);
+        }
+        if !online {
+            if let Some((node, peer_addr)) = self.cluster.lookup(&rf.id).await {
+                return Ok((Some(node), peer_addr));
+            }
+        }
+        Ok((None, peer_addr))
+    }
+
+    /// Whether a client may connect to `id` when `SAME_ORG` is set, peers
+    /// not owned by a hbba user are open to all, the others only to the
+    /// users of the same organization, known by their hbba token.
+    async fn check_org(&self, token: &str, id: &str) -> Result<(), &'static str> {
+        let owner = match self.pm.db.get_owner(id).await {
+            Ok(Some(owner)) => owner,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                log::error!("Failed to get the owner of {}: {}", id, err);
+                return Err("Server error");
+            }
+        };
+        let user = api_token::get_user(token).ok_or("Please log in to connect to this device")?;
+        if user == owner.0 {
+            return Ok(());
+        }
+        match self.pm.db.get_org(&user).await {
+            Ok(Some(org)) if org == owner.1 => Ok(()),
+            Ok(_) => Err("Not in the same organization"),
+            Err(err) => {
+                log::error!("Failed to get the organization of {}: {}", user, err);
+                Err("Server error")
+            }
+        }
+    }
+
+    /// Whether the connection rules allow a request from `addr` to `id`.
+    async fn check_policy(
+        &self,
+        addr: SocketAddr,
+        id: &str,
+        token: &str,
+        conn_type: Result<ConnType, i32>,
+    ) -> bool {
+        let rules = self.connection_policy.get().await;
+        if rules.rules.is_empty() {
+            return rules.default == Action::Allow;
+        }
+        let user = api_token::get_user(token);
+        let allowed = rules.allow(&Request {
+            ip: addr.ip(),
+            id,
+            user: user.as_deref(),
+            conn_type: conn_type.unwrap_or(ConnType::DEFAULT_CONN),
+            time: chrono::Local::now(),
+        });
+        if !allowed {
+            log::info!("Connection from {} to {} denied by the rules", addr, id);
+        }
+        allowed
+    }
+
     #[inline]
     async fn handle_udp_punch_hole_request(
         &mut self,
@@ -840,23 +1433,304 @@ impl RendezvousServer {
         true
     }
 
+    /// `host[:port][@weight],...`, a relay server with weight 2 takes twice
+    /// the load of the one with the default weight 1.
     fn parse_relay_servers(&mut self, relay_servers: &str) {
-        let rs = get_servers(relay_servers, "relay-servers");
+        let mut weights = HashMap::new();
+        let relay_servers: Vec<&str> = relay_servers
+            .split(",")
+            .map(|x| match x.split_once('@') {
+                Some((host, weight)) => {
+                    match weight.parse::<f64>() {
+                        Ok(w) if w > 0. => {
+                            weights.insert(host.to_owned(), w);
+                        }
+                        _ => log::error!("Invalid weight of relay server {}", x),
+                    }
+                    host
+                }
+                None => x,
+            })
+            .collect();
+        let rs = get_servers(&relay_servers.join(","), "relay-servers");
+        weights.retain(|x, _| rs.contains(x));
+        if !weights.is_empty() {
+            log::info!("relay-servers weights: {:?}", weights);
+        }
+        self.relay_weights = Arc::new(weights);
+        let locations: HashMap<String, (f64, f64)> = rs
+            .iter()
+            .filter_map(|x| {
+                let ip = test_if_valid_server(x, "relay-servers").ok()?.ip();
+                Some((x.clone(), geo::locate(ip)?))
+            })
+            .collect();
+        if !locations.is_empty() {
+            log::info!("relay-servers located: {:?}", locations);
+        }
+        self.relay_locations = Arc::new(locations);
+        self.relay_loads = Default::default();
+        self.relay_health = Default::default();
         self.relay_servers0 = Arc::new(rs);
         self.relay_servers = self.relay_servers0.clone();
     }
 
-    fn get_relay_server(&self, _pa: IpAddr, _pb: IpAddr) -> String {
+    /// Record the probes of the last check and keep the healthy relay
+    /// servers which are not draining, with their load, in the configured
+    /// order.
+    fn update_relay_servers(&mut self, rs: Vec<(String, Result<Probe, String>)>) {
+        let mut health = (*self.relay_health).clone();
+        let mut loads = HashMap::new();
+        for (x, probe) in rs.iter() {
+            if !self.relay_servers0.contains(x) {
+                continue;
+            }
+            let h = health.entry(x.clone()).or_default();
+            if h.update(probe) {
+                if h.healthy {
+                    log::info!("Relay server {} is healthy again", x);
+                } else {
+                    log::warn!("Relay server {} is unhealthy: {}", x, h.error);
+                }
+            }
+            if let Ok(Probe {
+                load: Some(load), ..
+            }) = probe
+            {
+                loads.insert(x.clone(), *load);
+            }
+        }
+        let relay_servers: RelayServers = self
+            .relay_servers0
+            .iter()
+            .filter(|x| health.get(*x).map(|h| h.healthy).unwrap_or(true))
+            .filter(|x| !loads.get(*x).map(|l| l.draining).unwrap_or(false))
+            .cloned()
+            .collect();
+        self.relay_health = Arc::new(health);
+        if relay_servers.is_empty() {
+            log::warn!("No relay server available, keep the last ones");
+            return;
+        }
+        self.relay_loads = Arc::new(loads);
+        self.relay_servers = Arc::new(relay_servers);
+    }
+
+    /// The configured relay servers in the form `parse_relay_servers` takes.
+    fn relay_servers_arg(&self) -> String {
+        self.relay_servers0
+            .iter()
+            .map(|x| match self.relay_weights.get(x) {
+                Some(w) => format!("{}@{}", x, w),
+                None => x.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// Reload the GeoIP database and locate the relay servers again.
+    pub(crate) fn reload_geo(&self) -> ResultType<()> {
+        let file = get_arg("geoip-db");
+        if file.is_empty() {
+            hbb_common::bail!("geoip-db is not set");
+        }
+        geo::load(&file)?;
+        self.tx
+            .send(Data::RelayServers0(self.relay_servers_arg()))
+            .ok();
+        Ok(())
+    }
+
+    /// The relay server of a peer pair, picked by weighted rendezvous hashing
+    /// among the relay servers near the peers, or all if they can not be
+    /// located, so that the retries of a pair land on the same relay server
+    /// and losing one only remaps the pairs it had, see `pick_relay_server`
+    /// for the load.
+    ///
+    /// `id` is the id of the peer to connect to, the requesting peer is only
+    /// known by its address `pa`.
+    pub(crate) fn get_relay_server(&self, id: &str, pa: IpAddr, pb: IpAddr) -> String {
         if self.relay_servers.is_empty() {
             return "".to_owned();
         } else if self.relay_servers.len() == 1 {
             return self.relay_servers[0].clone();
         }
-        let i = unsafe {
-            ROTATION_RELAY_SERVER += 1;
-            ROTATION_RELAY_SERVER % self.relay_servers.len()
-        };
-        self.relay_servers[i].clone()
+        let candidates: Vec<(&str, f64, Option<RelayLoad>)> = self
+            .get_near_relay_servers(pa, pb)
+            .unwrap_or_else(|| self.relay_servers.iter().collect())
+            .into_iter()
+            .map(|x| {
+                (
+                    x.as_str(),
+                    self.relay_weights.get(x).cloned().unwrap_or(1.),
+                    self.relay_loads.get(x).cloned(),
+                )
+            })
+            .collect();
+        pick_relay_server(&pair_key(id, pa, pb), &candidates)
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    /// The located relay servers within `GEO_TOLERANCE` of the shortest
+    /// total distance to the located peers, none if no peer or relay server
+    /// is located.
+    fn get_near_relay_servers(&self, pa: IpAddr, pb: IpAddr) -> Option<Vec<&String>> {
+        if self.relay_locations.is_empty() {
+            return None;
+        }
+        let peers: Vec<(f64, f64)> = [pa, pb].iter().filter_map(|x| geo::locate(*x)).collect();
+        if peers.is_empty() {
+            return None;
+        }
+        let distances: Vec<(&String, f64)> = self
+            .relay_servers
+            .iter()
+            .filter_map(|x| {
+                let location = self.relay_locations.get(x)?;
+                let d: f64 = peers.iter().map(|p| geo::distance(*p, *location)).sum();
+                Some((x, d))
+            })
+            .collect();
+        let min = distances.iter().map(|x| x.1).reduce(f64::min)?;
+        Some(
+            distances
+                .into_iter()
+                .filter(|x| x.1 <= min + GEO_TOLERANCE * peers.len() as f64)
+                .map(|x| x.0)
+                .collect(),
+        )
+    }
+
+    /// The healthy relay servers with their weight and reported load.
+    pub(crate) fn relay_loads(&self) -> Vec<(String, f64, Option<RelayLoad>)> {
+        self.relay_servers
+            .iter()
+            .map(|x| {
+                (
+                    x.clone(),
+                    self.relay_weights.get(x).cloned().unwrap_or(1.),
+                    self.relay_loads.get(x).cloned(),
+                )
+            })
+            .collect()
+    }
+
+    /// The health history of the configured relay servers, none of those
+    /// not probed yet.
+    pub(crate) fn relay_health(&self) -> Vec<(String, Option<RelayHealth>)> {
+        self.relay_servers0
+            .iter()
+            .map(|x| (x.clone(), self.relay_health.get(x).cloned()))
+            .collect()
+    }
+
+    /// A copy of the server as currently seen by the io loop, for callers
+    /// living outside of it, e.g. the admin api.
+    pub(crate) async fn current(tx: &Sender) -> Option<Self> {
+        let (res, rx) = oneshot::channel();
+        tx.send(Data::Server(res)).ok()?;
+        rx.await.ok()
+    }
+
+    #[inline]
+    pub(crate) fn relay_servers(&self) -> Vec<String> {
+        (*self.relay_servers).clone()
+    }
+
+    #[inline]
+    pub(crate) fn set_relay_servers(tx: &Sender, relay_servers: &str) {
+        tx.send(Data::RelayServers0(relay_servers.to_owned())).ok();
+    }
+
+    #[inline]
+    pub(crate) fn always_use_relay() -> bool {
+        unsafe { ALWAYS_USE_RELAY }
+    }
+
+    #[inline]
+    pub(crate) fn set_always_use_relay(v: bool) {
+        unsafe { ALWAYS_USE_RELAY = v };
+    }
+
+    #[inline]
+    pub(crate) async fn connection_rules(&self) -> Rules {
+        (*self.connection_policy.get().await).clone()
+    }
+
+    #[inline]
+    pub(crate) async fn cluster_peers(&self) -> Vec<(SocketAddr, usize)> {
+        self.cluster.peer_counts().await
+    }
+
+    pub(crate) fn peer_map(&self) -> &PeerMap {
+        &self.pm
+    }
+
+    /// Disable or enable a peer, on the other nodes of the cluster too.
+    pub(crate) async fn set_peer_disabled(&self, id: &str, disabled: bool) -> ResultType<bool> {
+        let res = self.pm.set_disabled(id, disabled).await?;
+        if res {
+            self.cluster.status_changed(id).await;
+        }
+        Ok(res)
+    }
+
+    #[inline]
+    pub(crate) fn is_online(peer: &Peer) -> bool {
+        (peer.last_reg_time.elapsed().as_millis() as i32) < REG_TIMEOUT
+    }
+
+    /// Whether an online query carries the licence key, if hbbs has one.
+    #[inline]
+    pub(crate) fn check_online_key(&self, licence_key: &str) -> bool {
+        self.key.is_empty() || licence_key == self.key
+    }
+
+    /// Whether the peers are online and when they last registered, only the
+    /// peers in memory or on the other nodes of the cluster are known, at
+    /// most `MAX_ONLINE_QUERY` of them.
+    pub(crate) async fn online_states(&self, ids: &[String]) -> Vec<OnlineState> {
+        let now = now() as i64;
+        let mut states = Vec::new();
+        for id in ids.iter().take(MAX_ONLINE_QUERY) {
+            let mut state = OnlineState {
+                id: id.clone(),
+                ..Default::default()
+            };
+            let mut disabled = false;
+            if let Some(peer) = self.pm.get_in_memory(id).await {
+                let peer = peer.read().await;
+                disabled = peer.disabled;
+                if peer.socket_addr.port() != 0 && !peer.disabled {
+                    state.online = Self::is_online(&peer);
+                    state.last_seen = now - peer.last_reg_time.elapsed().as_secs() as i64;
+                }
+            }
+            if !state.online && !disabled && self.cluster.lookup(id).await.is_some() {
+                state.online = true;
+                state.last_seen = now;
+            }
+            states.push(state);
+        }
+        states
+    }
+
+    /// Refresh the gauges which are only computed on scrape.
+    pub(crate) async fn update_metrics(&self) {
+        let (in_memory, online) = self.pm.count(REG_TIMEOUT).await;
+        metrics::PEER_COUNT
+            .with_label_values(&["memory"])
+            .set(in_memory as _);
+        metrics::PEER_COUNT
+            .with_label_values(&["online"])
+            .set(online as _);
+        match self.pm.db.count_peers().await {
+            Ok(n) => metrics::PEER_COUNT
+                .with_label_values(&["registered"])
+                .set(n),
+            Err(err) => log::error!("db.count_peers failed: {}", err),
+        }
     }
 
     async fn check_cmd(&self, cmd: &str) -> String {
@@ -865,13 +1739,14 @@ impl RendezvousServer {
         match fds.next() {
             Some("h") => {
                 res = format!(
-                    "{}\n{}\n{}\n{}\n{}\n{}\n",
+                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                     "relay-servers(rs) <separated by ,>",
                     "reload-geo(rg)",
                     "ip-blocker(ib) [<ip>|<number>] [-]",
                     "ip-changes(ic) [<id>|<number>] [-]",
+                    "disable-peer(dp) <id> [-]",
                     "always-use-relay(aur)",
-                    "test-geo(tg) <ip1> <ip2>"
+                    "test-geo(tg) <ip1> [<ip2> [id]]"
                 )
             }
             Some("relay-servers" | "rs") => {
@@ -933,6 +1808,18 @@ impl RendezvousServer {
                     }
                 }
             }
+            Some("disable-peer" | "dp") => {
+                if let Some(id) = fds.next() {
+                    let disabled = fds.next() != Some("-");
+                    res = match self.set_peer_disabled(id, disabled).await {
+                        Ok(true) => {
+                            format!("{} {}\n", id, if disabled { "disabled" } else { "enabled" })
+                        }
+                        Ok(false) => "id not exist\n".to_owned(),
+                        Err(err) => format!("{}\n", err),
+                    };
+                }
+            }
             Some("ip-changes" | "ic") => {
                 let mut lock = IP_CHANGES.lock().await;
                 lock.retain(|&_, v| v.0.elapsed().as_secs() < IP_CHANGE_DUR_X2 && v.1.len() > 1);
@@ -967,6 +1854,12 @@ impl RendezvousServer {
                     }
                 }
             }
+            Some("reload-geo" | "rg") => {
+                res = match self.reload_geo() {
+                    Ok(_) => "ok\n".to_owned(),
+                    Err(err) => format!("{}\n", err),
+                };
+            }
             Some("always-use-relay" | "aur") => {
                 if let Some(rs) = fds.next() {
                     if rs.to_uppercase() == "Y" {
@@ -984,10 +1877,11 @@ impl RendezvousServer {
                     if let Ok(a) = rs.parse::<IpAddr>() {
                         if let Some(rs) = fds.next() {
                             if let Ok(b) = rs.parse::<IpAddr>() {
-                                res = format!("{:?}", self.get_relay_server(a, b));
+                                let id = fds.next().unwrap_or_default();
+                                res = format!("{:?}", self.get_relay_server(id, a, b));
                             }
                         } else {
-                            res = format!("{:?}", self.get_relay_server(a, a));
+                            res = format!("{:?}", self.get_relay_server("", a, a));
                         }
                     }
                 }
@@ -997,7 +1891,7 @@ impl RendezvousServer {
         res
     }
 
-    async fn handle_listener2(&self, stream: TcpStream, addr: SocketAddr) {
+    async fn handle_listener2(&self, stream: TcpStream, addr: SocketAddr, key: &str) {
         if addr.ip().to_string() == "127.0.0.1" {
             let rs = self.clone();
             tokio::spawn(async move {
@@ -1013,11 +1907,15 @@ impl RendezvousServer {
             return;
         }
         let stream = FramedStream::from(stream, addr);
+        let key = key.to_owned();
         tokio::spawn(async move {
             let mut stream = stream;
             if let Some(Ok(bytes)) = stream.next_timeout(30_000).await {
                 if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
-                    if let Some(rendezvous_message::Union::test_nat_request(_)) = msg_in.union {
+                    if let Some(rendezvous_message::Union::test_nat_request(tar)) = msg_in.union {
+                        if !check_key("test_nat_request", &tar.licence_key, &key, addr) {
+                            return;
+                        }
                         let mut msg_out = RendezvousMessage::new();
                         msg_out.set_test_nat_response(TestNatResponse {
                             port: addr.port() as _,
@@ -1133,39 +2031,210 @@ impl RendezvousServer {
     }
 }
 
-async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender) {
-    let mut futs = Vec::new();
-    let rs = Arc::new(Mutex::new(Vec::new()));
-    for x in rs0.iter() {
-        let mut host = x.to_owned();
-        if !host.contains(":") {
-            host = format!("{}:{}", host, hbb_common::config::RELAY_PORT);
-        }
-        let rs = rs.clone();
+async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender, key: String) {
+    let futs = rs0.iter().map(|x| {
         let x = x.clone();
-        futs.push(tokio::spawn(async move {
-            if FramedStream::new(&host, "0.0.0.0:0", CHECK_RELAY_TIMEOUT)
+        let key = key.clone();
+        tokio::spawn(async move {
+            let mut host = x.clone();
+            if !host.contains(":") {
+                host = format!("{}:{}", host, hbb_common::config::RELAY_PORT);
+            }
+            let probe = probe_relay(&host, &key)
                 .await
-                .is_ok()
-            {
-                rs.lock().await.push(x);
+                .map_err(|err| err.to_string());
+            (x, probe)
+        })
+    });
+    let rs: Vec<(String, Result<Probe, String>)> = join_all(futs)
+        .await
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .collect();
+    log::debug!("check_relay_servers: {:?}", rs);
+    tx.send(Data::RelayServers(rs)).ok();
+}
+
+/// The peers to check for going offline, each when it would expire if it
+/// did not register again.
+#[derive(Default)]
+struct OfflineWatch {
+    expiry: HashMap<String, Instant>,
+    /// with stale entries of the peers expiring later
+    queue: BinaryHeap<Reverse<(Instant, String)>>,
+}
+
+impl OfflineWatch {
+    fn watch(&mut self, id: String, expiry: Instant) {
+        if self.expiry.get(&id) != Some(&expiry) {
+            self.expiry.insert(id.clone(), expiry);
+            self.queue.push(Reverse((expiry, id)));
+        }
+    }
+
+    /// The peers due to be checked, they are not watched any more.
+    fn due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        while let Some(Reverse((expiry, _))) = self.queue.peek() {
+            if *expiry > now {
+                break;
             }
-        }));
+            if let Some(Reverse((expiry, id))) = self.queue.pop() {
+                if self.expiry.get(&id) == Some(&expiry) {
+                    self.expiry.remove(&id);
+                    due.push(id);
+                }
+            }
+        }
+        due
     }
-    join_all(futs).await;
-    log::debug!("check_relay_servers");
-    let rs = std::mem::replace(&mut *rs.lock().await, Default::default());
-    if !rs.is_empty() {
-        tx.send(Data::RelayServers(rs)).ok();
+}
+
+/// Emit the offline events, a peer goes offline silently when it stops
+/// registering, so the peers reported online are checked when they would
+/// expire, and watched again if they have registered since.
+async fn watch_offline(pm: PeerMap) {
+    let timeout = Duration::from_millis(REG_TIMEOUT as _);
+    let mut rx = events::subscribe();
+    let mut watch = OfflineWatch::default();
+    let now = Instant::now();
+    for (id, _) in pm.online_peers(REG_TIMEOUT).await {
+        watch.watch(id, now);
+    }
+    let mut timer = interval(Duration::from_millis(1_000));
+    loop {
+        tokio::select! {
+            res = rx.recv() => match res {
+                Ok(e) => {
+                    if e.event != Kind::Offline {
+                        watch.watch(e.id, Instant::now() + timeout);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let now = Instant::now();
+                    for (id, _) in pm.online_peers(REG_TIMEOUT).await {
+                        if !watch.expiry.contains_key(&id) {
+                            watch.watch(id, now);
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = timer.tick() => {
+                let due = watch.due(Instant::now());
+                if due.is_empty() {
+                    continue;
+                }
+                let peers = pm.get_many_in_memory(&due).await;
+                // evicted ones are left out, only offline peers are
+                for (id, peer) in due.into_iter().zip(peers) {
+                    if let Some(peer) = peer {
+                        let peer = peer.read().await;
+                        if RendezvousServer::is_online(&peer) {
+                            watch.watch(id, peer.last_reg_time + timeout);
+                        } else {
+                            events::emit(Kind::Offline, &id, peer.socket_addr, None);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Probe a relay server the way a peer uses it, a relay request with the
+/// licence key on both its tcp and websocket ports, answered with its load.
+///
+/// hbbr older than the probes takes the request for a peer waiting for its
+/// pair and never answers, like a hung one. Only with `LEGACY_RELAY` such a
+/// relay server is checked to accept tcp connections and has no load.
+async fn probe_relay(host: &str, key: &str) -> ResultType<Probe> {
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_request_relay(RequestRelay {
+        uuid: format!("{}{}", RELAY_PROBE_PREFIX, uuid::Uuid::new_v4()),
+        licence_key: key.to_owned(),
+        ..Default::default()
+    });
+    let tm = Instant::now();
+    let mut socket = FramedStream::new(host, "0.0.0.0:0", CHECK_RELAY_TIMEOUT).await?;
+    let connected = tm.elapsed().as_millis() as u64;
+    socket.send(&msg_out).await?;
+    let load = match timeout(CHECK_RELAY_TIMEOUT, socket.next()).await {
+        Ok(Some(Ok(bytes))) => parse_relay_status(&bytes)?,
+        Ok(Some(Err(err))) => hbb_common::bail!("tcp: {}", err),
+        Ok(None) => hbb_common::bail!("tcp: closed, wrong key"),
+        Err(_) if unsafe { LEGACY_RELAY } => {
+            log::debug!("No status from relay server {}, outdated hbbr?", host);
+            return check_latency(Probe {
+                load: None,
+                latency: connected,
+            });
+        }
+        Err(_) => hbb_common::bail!("tcp: timeout, hung or outdated hbbr"),
+    };
+    let latency = tm.elapsed().as_millis() as u64;
+
+    let (ip, port) = match host.rsplit_once(':') {
+        Some((ip, port)) => (ip, port.parse::<u16>()?),
+        None => hbb_common::bail!("invalid relay server {}", host),
+    };
+    let ws_host = format!("{}:{}", ip, port + 2);
+    let tm = Instant::now();
+    let stream = timeout(CHECK_RELAY_TIMEOUT, TcpStream::connect(&ws_host)).await??;
+    let (mut ws, _) = timeout(
+        CHECK_RELAY_TIMEOUT,
+        tokio_tungstenite::client_async(format!("ws://{}", ws_host), stream),
+    )
+    .await??;
+    ws.send(tungstenite::Message::Binary(msg_out.write_to_bytes()?))
+        .await?;
+    loop {
+        match timeout(CHECK_RELAY_TIMEOUT, ws.next()).await {
+            Ok(Some(Ok(tungstenite::Message::Binary(bytes)))) => {
+                parse_relay_status(&bytes)?;
+                break;
+            }
+            Ok(Some(Ok(tungstenite::Message::Close(_))) | None) => {
+                hbb_common::bail!("websocket: no reply, wrong key or outdated hbbr")
+            }
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(err))) => hbb_common::bail!("websocket: {}", err),
+            Err(_) => hbb_common::bail!("websocket: timeout"),
+        }
+    }
+    check_latency(Probe {
+        load: Some(load),
+        latency: latency.max(tm.elapsed().as_millis() as u64),
+    })
+}
+
+#[inline]
+fn check_latency(probe: Probe) -> ResultType<Probe> {
+    if probe.latency > unsafe { RELAY_SLOW } {
+        hbb_common::bail!("too slow, {}ms", probe.latency);
+    }
+    Ok(probe)
+}
+
+fn parse_relay_status(bytes: &[u8]) -> ResultType<RelayLoad> {
+    match RendezvousMessage::parse_from_bytes(bytes)?.union {
+        Some(rendezvous_message::Union::relay_status(rs)) => Ok(RelayLoad {
+            sessions: rs.sessions,
+            bandwidth: rs.bandwidth,
+            total_bandwidth: rs.total_bandwidth,
+            draining: rs.draining,
+        }),
+        _ => hbb_common::bail!("unexpected reply"),
     }
 }
 
 // temp solution to solve udp socket failure
-async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
+async fn test_hbbs(addr: SocketAddr, key: &str) -> ResultType<()> {
     let mut socket = FramedSocket::new("0.0.0.0:0").await?;
     let mut msg_out = RendezvousMessage::new();
     msg_out.set_register_peer(RegisterPeer {
         id: "(:test_hbbs:)".to_owned(),
+        licence_key: key.to_owned(),
         ..Default::default()
     });
     let mut last_time_recv = Instant::now();
@@ -1190,12 +2259,32 @@ async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
     }
 }
 
-// #[inline]
-// fn distance(a: &(i32, i32), b: &(i32, i32)) -> i32 {
-//     let dx = a.0 - b.0;
-//     let dy = a.1 - b.1;
-//     dx * dx + dy * dy
-// }
+/// Whether a message carries the licence key in strict mode, always true
+/// otherwise.
+#[inline]
+fn check_key(message: &str, licence_key: &str, key: &str, addr: SocketAddr) -> bool {
+    !unsafe { STRICT_KEY } || match_key(message, licence_key, key, addr)
+}
+
+/// Whether a message carries the licence key if the server has one, even
+/// without `STRICT_KEY`.
+fn match_key(message: &str, licence_key: &str, key: &str, addr: SocketAddr) -> bool {
+    if key.is_empty() || licence_key == key {
+        return true;
+    }
+    log::debug!("Licence mismatch of {} from {}", message, addr);
+    metrics::LICENCE_MISMATCHES
+        .with_label_values(&[message])
+        .inc();
+    false
+}
+
+/// Whether a session request carries a valid hbba token when
+/// `REQUIRE_TOKEN` is set, always true otherwise.
+#[inline]
+fn check_token(token: &str) -> bool {
+    !unsafe { REQUIRE_TOKEN } || api_token::get_user(token).is_some()
+}
 
 #[inline]
 async fn send_rk_res(
@@ -1203,6 +2292,9 @@ async fn send_rk_res(
     addr: SocketAddr,
     res: register_pk_response::Result,
 ) -> ResultType<()> {
+    metrics::REGISTER_PK
+        .with_label_values(&[&format!("{:?}", res).to_lowercase()])
+        .inc();
     let mut msg_out = RendezvousMessage::new();
     msg_out.set_register_pk_response(RegisterPkResponse {
         result: res.into(),
@@ -1210,3 +2302,262 @@ async fn send_rk_res(
     });
     socket.send(&msg_out, addr).await
 }
+
+/// Order independent key of a peer pair.
+fn pair_key(id: &str, pa: IpAddr, pb: IpAddr) -> String {
+    let (a, b) = if pa <= pb { (pa, pb) } else { (pb, pa) };
+    format!("{}/{}/{}", id, a, b)
+}
+
+/// The relay server of `key` among (relay server, weight, load), by
+/// rendezvous hashing with bounded loads: the first one by score that is
+/// not overloaded and has fewer sessions than `1 + RELAY_LOAD_SLACK` times
+/// its weighted share of them. Pairs only move off a relay server while it
+/// is loaded well above the others.
+fn pick_relay_server<'a>(
+    key: &str,
+    candidates: &[(&'a str, f64, Option<RelayLoad>)],
+) -> Option<&'a str> {
+    let mut scored: Vec<_> = candidates
+        .iter()
+        .map(|x| (x, hrw_score(key, x.0, x.1)))
+        .collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let sessions: f64 = candidates
+        .iter()
+        .filter_map(|x| x.2)
+        .map(|x| x.sessions.max(0) as f64)
+        .sum();
+    let weights: f64 = candidates.iter().map(|x| x.1).sum();
+    let overloaded =
+        |x: &(&str, f64, Option<RelayLoad>)| x.2.map(|x| x.overloaded()).unwrap_or(false);
+    let full = |x: &(&str, f64, Option<RelayLoad>)| match x.2 {
+        Some(load) if weights > 0. => {
+            // with the new session
+            let share = (1. + RELAY_LOAD_SLACK) * (sessions + 1.) * x.1 / weights;
+            load.sessions as f64 + 1. > share.ceil()
+        }
+        _ => false,
+    };
+    scored
+        .iter()
+        .find(|x| !overloaded(x.0) && !full(x.0))
+        .or_else(|| scored.iter().find(|x| !overloaded(x.0)))
+        .or_else(|| scored.first())
+        .map(|x| x.0 .0)
+}
+
+/// Weighted rendezvous hashing score, the node with the highest score
+/// owns the key. The hash is fixed, all the hbbs of a cluster must agree
+/// on it.
+fn hrw_score(key: &str, node: &str, weight: f64) -> f64 {
+    // FNV-1a, with a 0 between key and node so that they can not run together
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in key.bytes().chain(std::iter::once(0)).chain(node.bytes()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    // murmur3 finalizer, FNV-1a alone mixes the high bits poorly
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    // uniform in (0, 1)
+    let h = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    weight / -h.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pick<'a>(key: &str, nodes: &[&'a str]) -> &'a str {
+        nodes
+            .iter()
+            .max_by(|a, b| {
+                hrw_score(key, a, 1.)
+                    .partial_cmp(&hrw_score(key, b, 1.))
+                    .unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hrw() {
+        let nodes = ["a:21117", "b:21117", "c:21117", "d:21117"];
+        let keys: Vec<String> = (0..1000).map(|i| format!("{}", i)).collect();
+        let mut moved = 0;
+        for key in keys.iter() {
+            let x = pick(key, &nodes);
+            assert_eq!(x, pick(key, &nodes));
+            let y = pick(key, &nodes[1..]);
+            if x != "a:21117" {
+                assert_eq!(x, y);
+            } else {
+                moved += 1;
+            }
+        }
+        assert!(moved > 150 && moved < 350, "{}", moved);
+        let doubled = keys
+            .iter()
+            .filter(|key| {
+                let b = nodes[1..]
+                    .iter()
+                    .map(|x| hrw_score(key, x, 1.))
+                    .reduce(f64::max)
+                    .unwrap();
+                hrw_score(key, nodes[0], 2.) > b
+            })
+            .count();
+        assert!(doubled > 300 && doubled < 500, "{}", doubled);
+        // hbbs of different versions must pick the same relay server
+        let score = hrw_score("123/1.1.1.1/2.2.2.2", "a:21117", 1.);
+        assert!((score - 16.9157).abs() < 1e-3, "{}", score);
+        let a: IpAddr = "1.1.1.1".parse().unwrap();
+        let b: IpAddr = "2.2.2.2".parse().unwrap();
+        assert_eq!(pair_key("123", a, b), pair_key("123", b, a));
+    }
+
+    #[test]
+    fn test_pick_relay_server() {
+        let load = |sessions: i32| {
+            Some(RelayLoad {
+                sessions,
+                ..Default::default()
+            })
+        };
+        let nodes = ["a:21117", "b:21117", "c:21117"];
+        let key = "123/1.1.1.1/2.2.2.2";
+        let pick = |loads: [Option<RelayLoad>; 3]| {
+            let candidates: Vec<_> = nodes
+                .iter()
+                .zip(loads)
+                .map(|(x, load)| (*x, 1., load))
+                .collect();
+            pick_relay_server(key, &candidates).unwrap()
+        };
+        let x = pick([None, None, None]);
+        let i = nodes.iter().position(|y| *y == x).unwrap();
+        let with = |n: i32, others: i32| {
+            let mut loads = [load(others), load(others), load(others)];
+            loads[i] = load(n);
+            loads
+        };
+        // stable while the loads are about even
+        assert_eq!(pick(with(0, 0)), x);
+        assert_eq!(pick(with(11, 9)), x);
+        // a loaded relay server is avoided
+        assert_ne!(pick(with(100, 0)), x);
+        assert_ne!(pick(with(20, 9)), x);
+        // and so is an overloaded one, unless all are
+        let mut loads = with(0, 0);
+        loads[i] = Some(RelayLoad {
+            bandwidth: 95.,
+            total_bandwidth: 100.,
+            ..Default::default()
+        });
+        assert_ne!(pick(loads), x);
+        let draining = Some(RelayLoad {
+            draining: true,
+            ..Default::default()
+        });
+        assert_eq!(pick([draining, draining, draining]), x);
+    }
+
+    #[test]
+    fn test_offline_watch() {
+        let t = Instant::now();
+        let s = |x: u64| t + Duration::from_secs(x);
+        let mut w = OfflineWatch::default();
+        w.watch("a".to_owned(), s(1));
+        w.watch("b".to_owned(), s(2));
+        w.watch("a".to_owned(), s(3));
+        assert!(w.due(t).is_empty());
+        assert_eq!(w.due(s(2)), vec!["b".to_owned()]);
+        w.watch("b".to_owned(), s(2));
+        assert_eq!(w.due(s(3)), vec!["b".to_owned(), "a".to_owned()]);
+        assert!(w.due(s(4)).is_empty());
+        assert!(w.expiry.is_empty() && w.queue.is_empty());
+    }
+
+    fn test_server(pm: PeerMap) -> RendezvousServer {
+        let (tx, _) = mpsc::unbounded_channel();
+        RendezvousServer {
+            tcp_punch: Default::default(),
+            pm,
+            tx,
+            relay_servers: Default::default(),
+            relay_servers0: Default::default(),
+            relay_locations: Default::default(),
+            relay_weights: Default::default(),
+            relay_loads: Default::default(),
+            relay_health: Default::default(),
+            connection_policy: Arc::new(JsonFile::new("")),
+            cluster: Arc::new(Cluster::new("", "")),
+            serial: 0,
+            rendezvous_servers: Default::default(),
+            version: Default::default(),
+            software_url: Default::default(),
+            sk: None,
+            key: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_relay_request() {
+        relay_request();
+    }
+
+    #[tokio::main(flavor = "multi_thread")]
+    async fn relay_request() {
+        let file = std::env::temp_dir().join("hbbs_test_relay_request.sqlite3");
+        std::fs::remove_file(&file).ok();
+        let db = crate::database::Database::new(file.to_str().unwrap())
+            .await
+            .unwrap();
+        db.insert_peer("test_1", &vec![], &vec![], "")
+            .await
+            .unwrap();
+        let rs = test_server(PeerMap::with_db(Arc::new(db)));
+        let addr = "1.1.1.1:1".parse().unwrap();
+        let rf = RequestRelay {
+            id: "test_1".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            rs.check_relay_request(addr, &rf, "").await,
+            Err(OFFLINE_FAILURE)
+        );
+        rs.pm.get("test_1").await.unwrap();
+        assert!(rs.check_relay_request(addr, &rf, "").await.is_ok());
+        assert!(rs.pm.set_disabled("test_1", true).await.unwrap());
+        assert_eq!(
+            rs.check_relay_request(addr, &rf, "").await,
+            Err(DISABLED_FAILURE)
+        );
+        std::fs::remove_file(&file).ok();
+    }
+
+    #[test]
+    fn test_relay_health() {
+        let ok = Ok(Probe {
+            load: Default::default(),
+            latency: 10,
+        });
+        let err = Err("timeout".to_owned());
+        let mut h = RelayHealth::default();
+        assert!(!h.update(&err));
+        assert!(!h.update(&err));
+        assert!(!h.update(&ok));
+        assert!(!h.update(&err));
+        assert!(!h.update(&err));
+        assert!(h.update(&err));
+        assert!(!h.healthy);
+        assert!(!h.update(&ok));
+        assert!(!h.update(&err));
+        assert!(!h.update(&ok));
+        assert!(h.update(&ok));
+        assert!(h.healthy);
+    }
+}
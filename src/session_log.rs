@@ -0,0 +1,91 @@
+use crate::database::{new_pool, Pool};
+use hbb_common::ResultType;
+use std::ops::DerefMut;
+
+/// A finished relay session, times in unix secs.
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    pub(crate) uuid: String,
+    /// id of the peer to connect to, from the relay request
+    pub(crate) id: String,
+    /// the side which came second and triggered the pairing
+    pub(crate) addr: String,
+    pub(crate) peer_addr: String,
+    pub(crate) class: Option<String>,
+    pub(crate) started_at: i64,
+    pub(crate) ended_at: i64,
+    pub(crate) total_bytes: i64,
+    /// in kb/s
+    pub(crate) highest_speed: i64,
+    pub(crate) downgraded: bool,
+    pub(crate) blacklisted: bool,
+    pub(crate) close_reason: String,
+}
+
+/// Relay sessions recorded in sqlite, for billing, audits and abuse
+/// investigations.
+#[derive(Clone)]
+pub(crate) struct SessionLog {
+    pool: Pool,
+}
+
+impl SessionLog {
+    pub(crate) async fn new(url: &str) -> ResultType<Self> {
+        let db = Self {
+            pool: new_pool(url).await?,
+        };
+        db.create_tables().await?;
+        Ok(db)
+    }
+
+    async fn create_tables(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists relay_session (
+                uuid varchar(100) not null,
+                id varchar(100) not null,
+                addr varchar(100) not null,
+                peer_addr varchar(100) not null,
+                class varchar(100),
+                started_at integer not null,
+                ended_at integer not null,
+                total_bytes integer not null,
+                highest_speed integer not null,
+                downgraded tinyint not null,
+                blacklisted tinyint not null,
+                close_reason text not null
+            );
+            create index if not exists index_relay_session_started_at on relay_session (started_at);
+            create index if not exists index_relay_session_id on relay_session (id);
+            create index if not exists index_relay_session_addr on relay_session (addr);
+            create index if not exists index_relay_session_peer_addr on relay_session (peer_addr);
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn insert(&self, s: &Session) -> ResultType<()> {
+        sqlx::query(
+            "insert into relay_session(uuid, id, addr, peer_addr, class, started_at, ended_at,
+                total_bytes, highest_speed, downgraded, blacklisted, close_reason)
+            values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&s.uuid)
+        .bind(&s.id)
+        .bind(&s.addr)
+        .bind(&s.peer_addr)
+        .bind(&s.class)
+        .bind(s.started_at)
+        .bind(s.ended_at)
+        .bind(s.total_bytes)
+        .bind(s.highest_speed)
+        .bind(s.downgraded)
+        .bind(s.blacklisted)
+        .bind(&s.close_reason)
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+}